@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+
+use tokio::net::TcpStream;
+
+use crate::{
+    connect::{self, socks5_proto, Deadline},
+    ConnectError, ConnectOptions, NetworkTarget, Proxy, ProxyKind, ProxyStream, Stage,
+};
+
+/**
+Pending inbound connection through SOCKS5 proxy (`BIND` command)
+
+Proxy listens on [`ProxiedListener::bound_addr`], which should be passed to the remote side
+(e.g. in FTP `PORT` command), and relays the single connection it accepts.
+
+```rust,no_run
+# async fn run() -> Result<(), Box<dyn std::error::Error>> {
+use std::str::FromStr;
+use proxied::{NetworkTarget, Proxy};
+
+let proxy = Proxy::from_str("socks5://127.0.0.1:1080")?;
+let ftp_server = NetworkTarget::IPAddr { socket: "10.0.0.1:21".parse()? };
+
+let listener = proxy.bind_tcp(ftp_server).await?;
+println!("waiting for connection on {}", listener.bound_addr());
+
+let (stream, peer) = listener.accept().await?;
+# Ok(())
+# }
+```
+*/
+#[derive(Debug)]
+pub struct ProxiedListener {
+    stream: TcpStream,
+    bound: NetworkTarget,
+}
+
+impl ProxiedListener {
+    pub(crate) async fn bind(
+        proxy: &Proxy,
+        target: NetworkTarget,
+        options: &ConnectOptions,
+    ) -> Result<Self, ConnectError> {
+        if !matches!(proxy.kind, ProxyKind::Socks5 | ProxyKind::Socks5h) {
+            return Err(ConnectError::CommandUnsupported);
+        }
+
+        let deadline = Deadline::after(options.deadline);
+        let mut stream = connect::connect_proxy(proxy, options, deadline).await?;
+
+        let deadline = deadline.min(Deadline::after(options.handshake_timeout));
+        let target = connect::resolve_target(proxy, target, options, deadline).await?;
+        socks5_proto::greet(proxy, &mut stream, deadline).await?;
+
+        let bound = deadline
+            .run(
+                Stage::Request,
+                socks5_proto::request(&mut stream, socks5_proto::SOCKS5_CMD_BIND, &target),
+            )
+            .await?;
+
+        // unspecified address means "same host as the proxy"
+        let bound = match bound {
+            NetworkTarget::IPAddr { socket } if socket.ip().is_unspecified() => {
+                let proxy_addr = stream
+                    .peer_addr()
+                    .map_err(ConnectError::io(Stage::Request))?;
+
+                NetworkTarget::IPAddr {
+                    socket: SocketAddr::new(proxy_addr.ip(), socket.port()),
+                }
+            }
+            bound => bound,
+        };
+
+        Ok(Self { stream, bound })
+    }
+
+    /// Address, proxy listens on for the inbound connection (from the first reply)
+    pub fn bound_addr(&self) -> &NetworkTarget {
+        &self.bound
+    }
+
+    /// Waits for the inbound connection, returning it with address of the connected peer
+    ///
+    /// There is no time limit, wrap it in [`tokio::time::timeout`] if needed.
+    pub async fn accept(mut self) -> Result<(ProxyStream, NetworkTarget), ConnectError> {
+        let peer = socks5_proto::read_reply(&mut self.stream).await?;
+
+        Ok((ProxyStream::Plain(self.stream), peer))
+    }
+}
@@ -0,0 +1,246 @@
+use std::{future::Future, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{Blame, ConnectError};
+
+/// Settings of [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BreakerConfig {
+    /// Failures in a row, which open the circuit
+    pub failure_threshold: u32,
+    /// How long circuit stays open, before trial connections are let through
+    pub open_for: Duration,
+    /// Trial connections, allowed at once while circuit is half-open, at least one
+    pub half_open_trials: u32,
+    /// Successful trials, which close the circuit
+    pub success_threshold: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+            half_open_trials: 1,
+            success_threshold: 1,
+        }
+    }
+}
+
+/// State of [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CircuitState {
+    /// Connections go through, failures are counted
+    Closed,
+    /// Connections are rejected with [`ConnectError::CircuitOpen`]
+    Open,
+    /// Few trial connections go through, to find out whether proxy has recovered
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { trials: u32, successes: u32 },
+}
+
+/**
+Circuit breaker of a single proxy
+
+Once proxy fails `failure_threshold` times in a row, circuit opens and connections are
+rejected right away, rather than waiting for the proxy to time out. After `open_for`,
+circuit becomes half-open and lets trial connections through: success closes it,
+while failure opens it again.
+
+Failures are [`ConnectError`]s blamed on the proxy (see [`ConnectError::blame`]), along with
+ones reported by [`CircuitBreaker::record_failure`], e.g. when proxy serves blocked pages.
+Errors blamed on the target or the caller count neither as failures nor as successes.
+Breakers of [`ProxyPool`](crate::ProxyPool) are enabled by
+[`ProxyPool::with_circuit_breaker`](crate::ProxyPool::with_circuit_breaker).
+
+```rust,no_run
+# async fn run() -> Result<(), proxied::ConnectError> {
+use std::str::FromStr;
+use proxied::{BreakerConfig, CircuitBreaker, NetworkTarget, Proxy};
+
+let proxy = Proxy::from_str("socks5://127.0.0.1:1080").unwrap();
+let breaker = CircuitBreaker::new(BreakerConfig::default());
+
+let target = NetworkTarget::Domain {
+    domain: "example.com".to_string(),
+    port: 80,
+};
+let stream = breaker.run(proxy.connect_tcp(target)).await?;
+# Ok(())
+# }
+```
+*/
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<State>,
+}
+
+/// Permission for a single connection, its outcome has to be recorded
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub(crate) fn record<T>(mut self, result: &Result<T, ConnectError>) {
+        match result {
+            Ok(_) => self.breaker.on_success(self.trial),
+            Err(error) if error.blame() == Blame::Proxy => self.breaker.on_failure(),
+            // errors of the target or the caller tell nothing about the proxy,
+            // so trial slot is just freed on drop
+            Err(_) => return,
+        }
+        self.recorded = true;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        // cancelled or inconclusive trial frees its slot
+        if self.trial && !self.recorded {
+            if let State::HalfOpen { trials, .. } = &mut *self.breaker.lock() {
+                *trials = trials.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Circuit, which starts closed, zero `half_open_trials` is raised to one
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config: BreakerConfig {
+                half_open_trials: config.half_open_trials.max(1),
+                ..config
+            },
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn config(&self) -> &BreakerConfig {
+        &self.config
+    }
+
+    /// Current state, open circuit becomes half-open once `open_for` passes
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { until } if until > Instant::now() => CircuitState::Open,
+            State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether connection would be let through right now
+    pub fn is_available(&self) -> bool {
+        match *self.lock() {
+            State::Closed { .. } => true,
+            State::Open { until } => until <= Instant::now(),
+            State::HalfOpen { trials, .. } => trials < self.config.half_open_trials,
+        }
+    }
+
+    /// Runs `connect` if circuit lets it through, and records its outcome
+    pub async fn run<T>(
+        &self,
+        connect: impl Future<Output = Result<T, ConnectError>>,
+    ) -> Result<T, ConnectError> {
+        let permit = self.acquire()?;
+        let result = connect.await;
+        permit.record(&result);
+        result
+    }
+
+    /// Counts failure, which isn't reported by [`ConnectError`], e.g. broken tunnel
+    pub fn record_failure(&self) {
+        self.on_failure();
+    }
+
+    /// Counts success of the proxy, resetting failures in a row
+    pub fn record_success(&self) {
+        self.on_success(false);
+    }
+
+    /// Closes the circuit, forgetting failures
+    pub fn reset(&self) {
+        *self.lock() = State::Closed { failures: 0 };
+    }
+
+    pub(crate) fn acquire(&self) -> Result<Permit<'_>, ConnectError> {
+        let mut state = self.lock();
+        let now = Instant::now();
+
+        if let State::Open { until } = *state {
+            if until > now {
+                return Err(ConnectError::CircuitOpen);
+            }
+            *state = State::HalfOpen {
+                trials: 0,
+                successes: 0,
+            };
+        }
+
+        let trial = match &mut *state {
+            State::HalfOpen { trials, .. } if *trials < self.config.half_open_trials => {
+                *trials += 1;
+                true
+            }
+            State::HalfOpen { .. } => return Err(ConnectError::CircuitOpen),
+            _ => false,
+        };
+
+        Ok(Permit {
+            breaker: self,
+            trial,
+            recorded: false,
+        })
+    }
+
+    fn on_success(&self, trial: bool) {
+        let mut state = self.lock();
+        match &mut *state {
+            State::Closed { failures } => *failures = 0,
+            // successes before the circuit opened tell nothing about recovery
+            State::HalfOpen { trials, successes } if trial => {
+                *trials = trials.saturating_sub(1);
+                *successes += 1;
+                if *successes >= self.config.success_threshold {
+                    *state = State::Closed { failures: 0 };
+                }
+            }
+            State::HalfOpen { .. } | State::Open { .. } => {}
+        }
+    }
+
+    fn on_failure(&self) {
+        let mut state = self.lock();
+        let open = State::Open {
+            until: Instant::now() + self.config.open_for,
+        };
+
+        match &mut *state {
+            State::Closed { failures } => {
+                *failures += 1;
+                if *failures >= self.config.failure_threshold {
+                    *state = open;
+                }
+            }
+            State::HalfOpen { .. } => *state = open,
+            State::Open { .. } => {}
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,116 @@
+use std::net::SocketAddr;
+
+use crate::{
+    connect::{self, Deadline},
+    BoxedStream, ConnectError, ConnectOptions, NetworkTarget, ProxiedStream, Proxy,
+};
+
+/**
+Sequence of proxies, each next one is reached through the tunnel of the previous
+
+TCP connection is opened only to the first hop, while every other hop is negotiated over
+the tunnel, built so far. Proxies of any kind can be mixed, e.g. SOCKS5 → HTTP `CONNECT` → SOCKS5.
+
+```rust,no_run
+# async fn run() -> Result<(), proxied::ConnectError> {
+use std::str::FromStr;
+use proxied::{NetworkTarget, Proxy, ProxyChain};
+
+let chain = ProxyChain::new(vec![
+    Proxy::from_str("socks5://127.0.0.1:1080").unwrap(),
+    Proxy::from_str("http://10.0.0.1:8080").unwrap(),
+])
+.unwrap();
+
+let stream = chain
+    .connect_tcp(NetworkTarget::Domain {
+        domain: "example.com".to_string(),
+        port: 80,
+    })
+    .await?;
+# Ok(())
+# }
+```
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProxyChain {
+    hops: Vec<Proxy>,
+}
+
+/// Address of the proxy, as a target for the previous hop
+fn hop_target(proxy: &Proxy) -> NetworkTarget {
+    match proxy.addr.ip() {
+        Some(ip) => NetworkTarget::IPAddr {
+            socket: SocketAddr::new(ip, proxy.port),
+        },
+        None => NetworkTarget::Domain {
+            domain: proxy.addr.to_string(),
+            port: proxy.port,
+        },
+    }
+}
+
+impl ProxyChain {
+    /// Create chain from proxies in order of traversal, `None` if there are no proxies
+    pub fn new(hops: Vec<Proxy>) -> Option<Self> {
+        match hops.is_empty() {
+            true => None,
+            false => Some(Self { hops }),
+        }
+    }
+
+    /// Proxies in order of traversal
+    pub fn hops(&self) -> &[Proxy] {
+        &self.hops
+    }
+
+    /// Create TCP tunnel through every proxy of the chain to the target
+    pub async fn connect_tcp(
+        &self,
+        target: NetworkTarget,
+    ) -> Result<ProxiedStream<BoxedStream>, ConnectError> {
+        self.connect_tcp_with(target, &ConnectOptions::default())
+            .await
+    }
+
+    /// Same as [`ProxyChain::connect_tcp`], but with custom [`ConnectOptions`]
+    ///
+    /// `deadline` limits the whole chain, while other timeouts are applied to each hop
+    pub async fn connect_tcp_with(
+        &self,
+        target: NetworkTarget,
+        options: &ConnectOptions,
+    ) -> Result<ProxiedStream<BoxedStream>, ConnectError> {
+        let last = self.hops.len() - 1;
+        let chain_error = |hop: usize| {
+            move |source: ConnectError| ConnectError::Chain {
+                hop,
+                last: hop == last,
+                source: Box::new(source),
+            }
+        };
+
+        let deadline = Deadline::after(options.deadline);
+        let mut stream: BoxedStream = Box::new(
+            connect::connect_proxy(&self.hops[0], options, deadline)
+                .await
+                .map_err(chain_error(0))?,
+        );
+
+        for (hop, pair) in self.hops.windows(2).enumerate() {
+            let next_target = hop_target(&pair[1]);
+
+            let hop_deadline = deadline.min(Deadline::after(options.handshake_timeout));
+            let tunnel = connect::handshake(&pair[0], next_target, options, stream, hop_deadline)
+                .await
+                .map_err(chain_error(hop))?;
+
+            stream = Box::new(tunnel);
+        }
+
+        let hop_deadline = deadline.min(Deadline::after(options.handshake_timeout));
+        connect::handshake(&self.hops[last], target, options, stream, hop_deadline)
+            .await
+            .map_err(chain_error(last))
+    }
+}
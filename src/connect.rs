@@ -1,24 +1,60 @@
-use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::LazyLock};
-
-use async_http_proxy::HttpError;
-use tokio::{net::TcpStream, sync::Mutex};
-
-use crate::{Proxy, ProxyKind};
-
-// TODO: refactor this to provide more details
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::Instant,
+};
+
+use crate::{
+    tls, HttpResponse, ProxiedStream, Proxy, ProxyHost, ProxyKind, ProxyStream, Resolver,
+    TlsConfig, TunnelInfo,
+};
+
+pub(crate) mod http_auth;
+pub(crate) mod http_proto;
+mod ntlm;
+mod socks4_proto;
+pub(crate) mod socks5_proto;
+
+pub use socks5_proto::Socks5Reply;
+
+/// Failure of the connection process
+///
+/// Every error is tied to the [`Stage`] it happened at (see [`ConnectError::stage`]),
+/// while [`ConnectError::blame`] and [`ConnectError::is_retryable`] help to decide what to do next.
 #[derive(thiserror::Error, Debug)]
 pub enum ConnectError {
-    #[error("No DNS records were present for this domain")]
-    DnsNameNotResolved,
+    #[error("No DNS records were present for this domain during {stage}")]
+    DnsNameNotResolved { stage: Stage },
+
+    #[error("Input/Output fail during {stage}")]
+    IO {
+        stage: Stage,
+        #[source]
+        source: std::io::Error,
+    },
 
-    #[error("Input/Output fail")]
-    IO(#[from] std::io::Error),
+    #[error("Timed out during {stage}")]
+    Timeout { stage: Stage },
 
-    #[error("HTTP tunnel failed to connect")]
-    Http(#[from] HttpError),
+    #[error("Circuit breaker of the proxy is open")]
+    CircuitOpen,
 
-    #[error("SOCKS tunnel failed to connect")]
-    Socks(#[from] fast_socks5::SocksError),
+    #[error("Every address of proxy failed: {}", format_attempts(.attempts))]
+    AllAddrsFailed {
+        /// Tried addresses of the proxy domain in order, along with their failures
+        attempts: Vec<(SocketAddr, ConnectError)>,
+    },
+
+    #[error("TLS handshake with proxy failed")]
+    Tls(#[source] std::io::Error),
 
     #[error("Authentication Failed")]
     AuthFailed { details: Option<String> },
@@ -26,17 +62,320 @@ pub enum ConnectError {
     #[error("Authentication method is unacceptable")]
     AuthMethodUnacceptable,
 
-    #[error("Failed proxy.addr parsing")]
-    FailedAddrParsing,
-
-    #[error("Wrong protocol used")]
-    WrongProtocol,
+    #[error("Wrong protocol used during {stage}")]
+    WrongProtocol { stage: Stage },
 
     #[error("Passed connection domain is too long")]
     ExceededMaxDomainLen,
+
+    #[error("Target address type is not supported by this proxy protocol")]
+    AddressTypeUnsupported,
+
+    #[error("Command is not supported by this proxy protocol")]
+    CommandUnsupported,
+
+    #[error("Invalid HTTP header {name:?}")]
+    InvalidHeader { name: String },
+
+    #[error("Invalid HTTP request: {details}")]
+    InvalidRequest { details: String },
+
+    #[error("SOCKS4 request rejected or failed")]
+    Socks4Rejected,
+
+    #[error("SOCKS5 request failed: {reply}")]
+    Socks5 { reply: Socks5Reply },
+
+    #[error("HTTP proxy responded with {status} {reason}")]
+    Http { status: u16, reason: String },
+
+    #[error("Proxy chain failed at hop {hop}")]
+    Chain {
+        /// Index of the proxy in chain, which failed
+        hop: usize,
+        /// Whether failed proxy is the last one, so it's target is the final target
+        last: bool,
+        #[source]
+        source: Box<ConnectError>,
+    },
+}
+
+/// Side which caused [`ConnectError`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Blame {
+    /// Proxy is unreachable, misbehaves or refused to serve us, other proxy may succeed
+    Proxy,
+    /// Proxy works, but target can't be reached through it
+    Target,
+    /// Request itself is invalid, no proxy would succeed
+    Caller,
+}
+
+impl ConnectError {
+    pub(crate) fn io(stage: Stage) -> impl FnOnce(std::io::Error) -> Self {
+        move |source| Self::IO { stage, source }
+    }
+
+    /// Stage at which connection failed
+    pub fn stage(&self) -> Stage {
+        match self {
+            Self::DnsNameNotResolved { stage }
+            | Self::IO { stage, .. }
+            | Self::Timeout { stage }
+            | Self::WrongProtocol { stage } => *stage,
+            Self::AllAddrsFailed { .. } | Self::CircuitOpen => Stage::ProxyConnect,
+            Self::Tls(_) => Stage::TlsHandshake,
+            Self::AuthFailed { .. } => Stage::Auth,
+            Self::AuthMethodUnacceptable => Stage::Greeting,
+            Self::ExceededMaxDomainLen
+            | Self::AddressTypeUnsupported
+            | Self::CommandUnsupported
+            | Self::InvalidHeader { .. }
+            | Self::InvalidRequest { .. }
+            | Self::Socks4Rejected
+            | Self::Socks5 { .. }
+            | Self::Http { .. } => Stage::Request,
+            Self::Chain { source, .. } => source.stage(),
+        }
+    }
+
+    /// Which side is at fault
+    pub fn blame(&self) -> Blame {
+        match self {
+            Self::ExceededMaxDomainLen
+            | Self::AddressTypeUnsupported
+            | Self::CommandUnsupported
+            | Self::InvalidHeader { .. }
+            | Self::InvalidRequest { .. } => Blame::Caller,
+            Self::DnsNameNotResolved {
+                stage: Stage::TargetDns,
+            }
+            | Self::IO {
+                stage: Stage::TargetDns,
+                ..
+            } => Blame::Target,
+            // proxy has accepted the request, but target takes too long to answer,
+            // while proxy, which hasn't replied at all, is blamed by default
+            Self::Timeout {
+                stage: Stage::TargetDns | Stage::Request,
+            } => Blame::Target,
+            Self::Socks5 {
+                reply:
+                    Socks5Reply::NetworkUnreachable
+                    | Socks5Reply::HostUnreachable
+                    | Socks5Reply::ConnectionRefused
+                    | Socks5Reply::TtlExpired,
+            } => Blame::Target,
+            // 0x5B "rejected or failed" is the only SOCKS4 reply for unreachable target
+            Self::Socks4Rejected => Blame::Target,
+            // 502 Bad Gateway, 504 Gateway Timeout and 404 are reported by proxy about target
+            Self::Http {
+                status: 404 | 502 | 504,
+                ..
+            } => Blame::Target,
+            // target of intermediate hop is the next proxy in chain
+            Self::Chain { last, source, .. } => match source.blame() {
+                Blame::Target if !last => Blame::Proxy,
+                blame => blame,
+            },
+            _ => Blame::Proxy,
+        }
+    }
+
+    /// Whether failure is likely transient, so the same attempt may succeed later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::IO { .. } | Self::Timeout { .. } => true,
+            Self::AllAddrsFailed { attempts } => {
+                attempts.iter().any(|(_, error)| error.is_retryable())
+            }
+            Self::Socks5 { reply } => matches!(
+                reply,
+                Socks5Reply::GeneralFailure
+                    | Socks5Reply::NetworkUnreachable
+                    | Socks5Reply::HostUnreachable
+                    | Socks5Reply::TtlExpired
+            ),
+            Self::Http { status, .. } => matches!(status, 408 | 429 | 500 | 502 | 503 | 504),
+            Self::Chain { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+fn format_attempts(attempts: &[(SocketAddr, ConnectError)]) -> String {
+    let attempts: Vec<String> = attempts
+        .iter()
+        .map(|(addr, error)| format!("{} ({})", addr, error))
+        .collect();
+    attempts.join(", ")
+}
+
+/// Phase of the connection process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Resolving proxy address
+    ProxyDns,
+    /// Establishing TCP connection to the proxy
+    ProxyConnect,
+    /// TLS session establishment with [`ProxyKind::Https`] proxy
+    TlsHandshake,
+    /// Protocol version and authentication method negotiation
+    Greeting,
+    /// Authentication on the proxy
+    Auth,
+    /// Resolving target domain locally, for protocols not capable to pass it to the proxy
+    TargetDns,
+    /// Waiting for the first byte of reply to HTTP `CONNECT` or SOCKS4 request,
+    /// as these protocols have no greeting to tell silent proxy from slow target
+    Reply,
+    /// Tunnel request (e.g. `CONNECT`), until proxy reports that the target is reached
+    Request,
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Stage::ProxyDns => "proxy DNS resolution",
+            Stage::ProxyConnect => "proxy TCP connect",
+            Stage::TlsHandshake => "proxy TLS handshake",
+            Stage::Greeting => "proxy greeting",
+            Stage::Auth => "proxy authentication",
+            Stage::TargetDns => "target DNS resolution",
+            Stage::Reply => "proxy reply",
+            Stage::Request => "tunnel request",
+        })
+    }
+}
+
+/// Additional settings of the connection process
+///
+/// Every timeout is disabled by default. When both stage timeout and `deadline` are set,
+/// whichever expires first wins and is reported as [`ConnectError::Timeout`] of the current stage.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// TLS settings for [`ProxyKind::Https`] proxies
+    pub tls: TlsConfig,
+
+    /// Where domain targets are resolved
+    pub dns: DnsMode,
+
+    /// Resolver of proxy addresses and of targets with local [`DnsMode`]
+    pub resolver: Resolver,
+
+    /// Limit for each DNS resolution, of proxy address and of target with local [`DnsMode`]
+    pub dns_timeout: Option<Duration>,
+
+    /// Limit for TCP connection establishment to each address of the proxy
+    pub connect_timeout: Option<Duration>,
+
+    /// Racing of proxy addresses, when its domain resolves to several
+    pub happy_eyeballs: HappyEyeballs,
+
+    /// Limit for proxy handshake, until tunnel to the target is ready
+    pub handshake_timeout: Option<Duration>,
+
+    /// Limit for the whole connection process
+    pub deadline: Option<Duration>,
+}
+
+/**
+Where [`NetworkTarget::Domain`] is resolved
+
+Only applies to [`ProxyKind::Socks5`] and HTTP(s) proxies, as the rest can do only one:
+- [`ProxyKind::Socks4`] resolves locally, since protocol carries IPv4 address only
+- [`ProxyKind::Socks5h`] and [`ProxyKind::Socks4a`] always let the proxy resolve it,
+  so the domain never leaks to the local resolver
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DnsMode {
+    /// Domain is passed to the proxy as is
+    #[default]
+    Remote,
+    /// Domain is resolved locally, proxy only sees the address
+    Local,
+    /// Domain is resolved locally, falling back to the proxy if that fails
+    LocalThenRemote,
+}
+
+/**
+Connection attempts to the proxy addresses (Happy Eyeballs v2, RFC 8305)
+
+Addresses alternate between families, starting with the preferred one. Each next
+attempt starts once previous one fails, or `delay` passes while it is still pending,
+so broken IPv6 costs only the `delay` rather than the whole connect timeout.
+First established connection wins, the rest are dropped.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HappyEyeballs {
+    /// Connection Attempt Delay, `None` tries addresses strictly one after another
+    pub delay: Option<Duration>,
+    /// Family tried first
+    pub preferred: AddrFamily,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        Self {
+            delay: Some(Duration::from_millis(250)),
+            preferred: AddrFamily::Ipv6,
+        }
+    }
+}
+
+/// IP address family
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AddrFamily {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+impl AddrFamily {
+    pub(crate) fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+}
+
+/// Point in time, after which current stage fails with [`ConnectError::Timeout`]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub(crate) fn after(timeout: Option<Duration>) -> Self {
+        Self(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    /// Earliest of two deadlines
+    pub(crate) fn min(self, other: Self) -> Self {
+        match (self.0, other.0) {
+            (Some(left), Some(right)) => Self(Some(left.min(right))),
+            (left, right) => Self(left.or(right)),
+        }
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.0.is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    pub(crate) async fn run<T>(
+        self,
+        stage: Stage,
+        future: impl std::future::Future<Output = Result<T, ConnectError>>,
+    ) -> Result<T, ConnectError> {
+        match self.0 {
+            Some(deadline) => tokio::time::timeout_at(deadline, future)
+                .await
+                .map_err(|_| ConnectError::Timeout { stage })?,
+            None => future.await,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// Target for proxy for connection, in form of DNS name or socket's IP Address
 ///
 /// Each Domain target is cached, and if you make multiple connections
@@ -75,237 +414,255 @@ impl std::fmt::Display for NetworkTarget {
         }
     }
 }
+/// What proxy has reported, while establishing the tunnel
+#[derive(Debug, Default)]
+pub(crate) struct Negotiated {
+    pub(crate) bound_addr: Option<NetworkTarget>,
+    pub(crate) http_response: Option<HttpResponse>,
+    /// Tunnel data, received along with the handshake reply
+    pub(crate) leftover: Vec<u8>,
+}
+
 trait ProxyProto {
-    async fn new(
+    async fn new<S: AsyncRead + AsyncWrite + Unpin>(
         proxy: &Proxy,
         target: NetworkTarget,
-        proxy_stream: &mut tokio::net::TcpStream,
-    ) -> Result<(), ConnectError>;
+        proxy_stream: &mut S,
+        deadline: Deadline,
+    ) -> Result<Negotiated, ConnectError>;
 }
 
-mod socks_proto {
-    use fast_socks5::{client::Config, util::target_addr::TargetAddr, AuthenticationMethod};
-    use tokio::net::TcpStream;
-
-    use crate::Proxy;
-
-    use super::{ConnectError, NetworkTarget, ProxyProto};
+/// Applies [`DnsMode`] to the `target` of `proxy`, resolving domain locally if needed
+pub(crate) async fn resolve_target(
+    proxy: &Proxy,
+    target: NetworkTarget,
+    options: &ConnectOptions,
+    deadline: Deadline,
+) -> Result<NetworkTarget, ConnectError> {
+    let (mode, filter): (_, fn(&IpAddr) -> bool) = match proxy.kind {
+        ProxyKind::Socks5h | ProxyKind::Socks4a => (DnsMode::Remote, |_| true),
+        // SOCKS4 protocol carries IPv4 address only
+        ProxyKind::Socks4 => (DnsMode::Local, IpAddr::is_ipv4),
+        _ => (options.dns, |_| true),
+    };
+    let NetworkTarget::Domain { domain, port } = &target else {
+        return Ok(target);
+    };
+    if mode == DnsMode::Remote {
+        return Ok(target);
+    }
 
-    impl From<NetworkTarget> for TargetAddr {
-        fn from(val: NetworkTarget) -> Self {
-            match val {
-                NetworkTarget::Domain { domain, port } => TargetAddr::Domain(domain, port),
-                NetworkTarget::IPAddr { socket } => TargetAddr::Ip(socket),
-            }
-        }
+    let resolved = deadline
+        .min(Deadline::after(options.dns_timeout))
+        .run(
+            Stage::TargetDns,
+            options
+                .resolver
+                .lookup_filtered(domain, *port, Stage::TargetDns, filter),
+        )
+        .await;
+
+    match resolved {
+        Ok(socket) => Ok(NetworkTarget::IPAddr { socket }),
+        Err(_) if mode == DnsMode::LocalThenRemote => Ok(target),
+        Err(error) => Err(error),
     }
+}
 
-    pub struct SocksProtocol;
-    impl ProxyProto for SocksProtocol {
-        async fn new(
-            proxy: &Proxy,
-            target: NetworkTarget,
-            proxy_stream: &mut TcpStream,
-        ) -> Result<(), ConnectError> {
-            let mut auth = None;
-            if let Some((username, password)) = &proxy.creds {
-                auth = Some(AuthenticationMethod::Password {
-                    username: username.clone(),
-                    password: password.clone(),
-                });
-            }
-            let stream = fast_socks5::client::Socks5Stream::use_stream(
-                proxy_stream,
-                auth,
-                Config::default(),
-            )
-            .await;
-
-            let mut stream = match stream {
-                Ok(stream) => stream,
-                Err(error) => match error {
-                    fast_socks5::SocksError::AuthMethodUnacceptable(_) => {
-                        return Err(ConnectError::AuthMethodUnacceptable);
-                    }
-                    fast_socks5::SocksError::UnsupportedSocksVersion(_) => {
-                        return Err(ConnectError::WrongProtocol);
-                    }
-                    fast_socks5::SocksError::AuthenticationFailed(details) => {
-                        return Err(ConnectError::AuthFailed {
-                            details: Some(details),
-                        });
-                    }
-                    fast_socks5::SocksError::AuthenticationRejected(details) => {
-                        return Err(ConnectError::AuthFailed {
-                            details: Some(details),
-                        });
-                    }
-
-                    err => return Err(err.into()),
-                },
-            };
-
-            let command_result = stream
-                .request(fast_socks5::Socks5Command::TCPConnect, target.into())
-                .await;
-
-            match command_result {
-                Ok(_) => Ok(()),
-                Err(fast_socks5::SocksError::ExceededMaxDomainLen(_)) => {
-                    Err(ConnectError::ExceededMaxDomainLen)
-                }
-                Err(e) => Err(e.into()),
-            }
+/// Runs only proxy protocol negotiation over `stream`, without TLS for [`ProxyKind::Https`]
+pub(crate) async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    target: NetworkTarget,
+    stream: &mut S,
+    deadline: Deadline,
+) -> Result<Negotiated, ConnectError> {
+    match &proxy.kind {
+        ProxyKind::Socks5 | ProxyKind::Socks5h => {
+            socks5_proto::Socks5Protocol::new(proxy, target, stream, deadline).await
+        }
+        ProxyKind::Socks4 | ProxyKind::Socks4a => {
+            socks4_proto::Socks4Protocol::new(proxy, target, stream, deadline).await
+        }
+        ProxyKind::Http | ProxyKind::Https => {
+            http_proto::HttpProtocol::new(proxy, target, stream, deadline).await
         }
     }
 }
 
-mod http_proto {
-    use async_http_proxy::HttpError;
-    use tokio::net::TcpStream;
-
-    use crate::Proxy;
-
-    use super::{ConnectError, NetworkTarget, ProxyProto};
-
-    pub struct HttpProtocol;
-    impl ProxyProto for HttpProtocol {
-        async fn new(
-            proxy: &Proxy,
-            target: NetworkTarget,
-            mut proxy_stream: &mut TcpStream,
-        ) -> Result<(), ConnectError> {
-            let host = target.host();
-            let resp = match &proxy.creds {
-                Some((login, password)) => {
-                    async_http_proxy::http_connect_tokio_with_basic_auth(
-                        &mut proxy_stream,
-                        host.as_str(),
-                        target.port(),
-                        login.as_str(),
-                        password.as_str(),
-                    )
-                    .await
-                }
-                None => {
-                    async_http_proxy::http_connect_tokio(
-                        &mut proxy_stream,
-                        host.as_str(),
-                        target.port(),
-                    )
-                    .await
-                }
-            };
-
-            match resp {
-                Ok(()) => (),
-                Err(HttpError::IoError(io)) => return Err(ConnectError::IO(io)),
-                Err(HttpError::HttpCode200(407)) => {
-                    return Err(ConnectError::AuthFailed { details: None })
-                }
-
-                Err(err) => return Err(err.into()),
-            }
+/// Runs proxy protocol over already established `stream` to the proxy
+///
+/// For [`ProxyKind::Https`] TLS session is established first
+pub(crate) async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    target: NetworkTarget,
+    options: &ConnectOptions,
+    mut stream: S,
+    deadline: Deadline,
+) -> Result<ProxiedStream<S>, ConnectError> {
+    let target = resolve_target(proxy, target, options, deadline).await?;
+
+    let (stream, negotiated) = match proxy.kind {
+        ProxyKind::Https => {
+            let mut stream = deadline
+                .run(
+                    Stage::TlsHandshake,
+                    tls::connect(stream, &proxy.addr, &options.tls),
+                )
+                .await?;
+            let negotiated = negotiate(proxy, target, &mut stream, deadline).await?;
+
+            (ProxyStream::Tls(Box::new(stream)), negotiated)
+        }
+        _ => {
+            let negotiated = negotiate(proxy, target, &mut stream, deadline).await?;
 
-            Ok(())
+            (ProxyStream::Plain(stream), negotiated)
         }
-    }
-}
+    };
 
-pub struct AddrRecord {
-    items: Vec<SocketAddr>,
-    next_item: usize,
+    let info = TunnelInfo {
+        proxy: proxy.clone(),
+        proxy_addr: None,
+        bound_addr: negotiated.bound_addr,
+        http_response: negotiated.http_response,
+    };
+    Ok(ProxiedStream::new(stream, info).with_leftover(negotiated.leftover))
 }
 
-const CACHE_SIZE: usize = 1_000;
-const CACHE_THRESHOLD: usize = CACHE_SIZE + CACHE_SIZE / 2;
-
-/// Cached names to perform round-robin in case that there are multiple connections to same domain
-static RESOLVED_DNS: LazyLock<Mutex<HashMap<String, AddrRecord>>> =
-    LazyLock::new(|| Mutex::new(HashMap::new()));
-
-async fn name_present_dns(record: &mut AddrRecord) -> Result<SocketAddr, ConnectError> {
-    if record.items.is_empty() {
-        Err(ConnectError::DnsNameNotResolved)
-    } else {
-        let current = record
-            .items
-            .get(record.next_item)
-            .ok_or(ConnectError::DnsNameNotResolved)?;
-        record.next_item += 1;
-
-        if record.next_item == record.items.len() {
-            record.next_item = 0;
+/// Resolves proxy address and opens TCP connection to it
+type Attempt<'a> = Pin<Box<dyn Future<Output = Result<TcpStream, ConnectError>> + Send + 'a>>;
+
+/// TCP connection to the proxy
+///
+/// Domain of the proxy may resolve to several addresses, then they are raced according to
+/// [`HappyEyeballs`], while failed ones are put into cooldown of [`Resolver`](crate::Resolver).
+/// Single failed address is reported as is, several as [`ConnectError::AllAddrsFailed`].
+pub(crate) async fn connect_proxy(
+    proxy: &Proxy,
+    options: &ConnectOptions,
+    deadline: Deadline,
+) -> Result<TcpStream, ConnectError> {
+    let (addrs, resolved) = match &proxy.addr {
+        ProxyHost::Domain(domain) => {
+            let addrs = deadline
+                .min(Deadline::after(options.dns_timeout))
+                .run(
+                    Stage::ProxyDns,
+                    options.resolver.lookup_all(
+                        domain,
+                        proxy.port,
+                        Stage::ProxyDns,
+                        options.happy_eyeballs.preferred,
+                    ),
+                )
+                .await?;
+            (addrs, true)
         }
+        ProxyHost::Ipv4(ip) => (vec![SocketAddr::new((*ip).into(), proxy.port)], false),
+        ProxyHost::Ipv6(ip) => (vec![SocketAddr::new((*ip).into(), proxy.port)], false),
+    };
 
-        Ok(*current)
-    }
-}
-async fn resolve_dns(domain: &String, port: u16) -> Result<SocketAddr, ConnectError> {
-    let mut records_lock = RESOLVED_DNS.lock().await;
-
-    // safety precaution not to fill all the heap with cache (very unlikely, but should be handle)
-    if records_lock.len() > CACHE_THRESHOLD {
-        let mut size_delta = records_lock.len() - CACHE_SIZE;
-        records_lock.retain(|_, _| {
-            if size_delta > 0 {
-                size_delta -= 1;
-                return false;
-            }
-            true
-        });
-    }
+    let mut pending = addrs.into_iter();
+    let mut racing: Vec<(SocketAddr, Attempt)> = Vec::new();
+    let mut attempts = Vec::new();
 
-    if let Some(record) = records_lock.get_mut(domain) {
-        name_present_dns(record).await
-    } else {
-        // free lock while resolving process takes places in order to give change other threads to lock  while we resolve and to avoid deadlock by reccurent locking
-        drop(records_lock);
-
-        let domain_name = format!("{}:{}", &domain, port);
-        let resolve_request = tokio::net::lookup_host(domain_name).await?.collect();
-
-        // kickstart lock
-        records_lock = RESOLVED_DNS.lock().await;
-
-        // check if it wasn't resolved by another thread in mean time
-        //
-        // it's needed because we can accidentally overwrite round robin state
-        // meaning that may be other threads already used `next_time` and updated it.
-        // although not critical, we don't want to lose this information
-
-        if !records_lock.contains_key(domain) {
-            records_lock.insert(
-                domain.clone(),
-                AddrRecord {
-                    items: resolve_request,
-                    next_item: 0,
-                },
-            );
+    loop {
+        // next attempt starts after failure of previous one, or once delay passes
+        match pending.next() {
+            Some(addr) => racing.push((addr, Box::pin(connect_addr(addr, options, deadline)))),
+            None if racing.is_empty() => break,
+            None => {}
+        }
+        let mut delay = options
+            .happy_eyeballs
+            .delay
+            .filter(|_| pending.len() > 0)
+            .map(|delay| Box::pin(tokio::time::sleep(delay)));
+
+        let finished = std::future::poll_fn(|cx| {
+            for index in 0..racing.len() {
+                if let Poll::Ready(finished) = racing[index].1.as_mut().poll(cx) {
+                    let (addr, _) = racing.swap_remove(index);
+                    return Poll::Ready(Some((addr, finished)));
+                }
+            }
+            match &mut delay {
+                Some(delay) => delay.as_mut().poll(cx).map(|_| None),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+
+        match finished {
+            None => {}
+            Some((addr, Ok(stream))) => {
+                if resolved {
+                    options.resolver.mark_alive(addr.ip());
+                }
+                return Ok(stream);
+            }
+            // whole connection ran out of time, rather than this address,
+            // so attempts still in flight are cut short as well
+            Some((addr, Err(error))) if deadline.is_expired() => {
+                attempts.push((addr, error));
+                attempts.extend(racing.drain(..).map(|(addr, _)| {
+                    let error = ConnectError::Timeout {
+                        stage: Stage::ProxyConnect,
+                    };
+                    (addr, error)
+                }));
+                break;
+            }
+            Some((addr, Err(error))) => {
+                if resolved {
+                    options.resolver.mark_dead(addr.ip());
+                }
+                attempts.push((addr, error));
+            }
         }
+    }
 
-        name_present_dns(records_lock.get_mut(domain).unwrap()).await
+    match attempts.len() {
+        1 => Err(attempts.remove(0).1),
+        _ => Err(ConnectError::AllAddrsFailed { attempts }),
     }
 }
 
-pub async fn connect(proxy: &Proxy, target: NetworkTarget) -> Result<TcpStream, ConnectError> {
-    let resolved_addr = match proxy.is_dns_addr() {
-        true => resolve_dns(&proxy.addr, proxy.port).await?,
-        false => SocketAddr::from_str(&format!("{}:{}", &proxy.addr, proxy.port))
-            .map_err(|_| ConnectError::FailedAddrParsing)?,
-    };
+async fn connect_addr(
+    addr: SocketAddr,
+    options: &ConnectOptions,
+    deadline: Deadline,
+) -> Result<TcpStream, ConnectError> {
+    let stream = deadline
+        .min(Deadline::after(options.connect_timeout))
+        .run(Stage::ProxyConnect, async {
+            TcpStream::connect(addr)
+                .await
+                .map_err(ConnectError::io(Stage::ProxyConnect))
+        })
+        .await?;
+    stream
+        .set_nodelay(true)
+        .map_err(ConnectError::io(Stage::ProxyConnect))?;
+    stream
+        .set_linger(None)
+        .map_err(ConnectError::io(Stage::ProxyConnect))?;
 
-    let mut stream: TcpStream = TcpStream::connect(resolved_addr).await?;
-    stream.set_nodelay(true)?;
-    stream.set_linger(None)?;
-    match &proxy.kind {
-        ProxyKind::Socks5 | ProxyKind::Socks4 => {
-            socks_proto::SocksProtocol::new(proxy, target, &mut stream).await?
-        }
-        ProxyKind::Http | ProxyKind::Https => {
-            http_proto::HttpProtocol::new(proxy, target, &mut stream).await?
-        }
-    }
+    Ok(stream)
+}
+
+pub async fn connect(
+    proxy: &Proxy,
+    target: NetworkTarget,
+    options: &ConnectOptions,
+) -> Result<ProxiedStream, ConnectError> {
+    let deadline = Deadline::after(options.deadline);
+    let stream = connect_proxy(proxy, options, deadline).await?;
+    let proxy_addr = stream.peer_addr().ok();
+
+    let deadline = deadline.min(Deadline::after(options.handshake_timeout));
+    let mut stream = handshake(proxy, target, options, stream, deadline).await?;
+    stream.info_mut().proxy_addr = proxy_addr;
 
     Ok(stream)
 }
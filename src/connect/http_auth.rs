@@ -0,0 +1,321 @@
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{HttpAuth, HttpResponse, Proxy};
+
+use super::{http_proto::validate_header, ntlm, ConnectError};
+
+/// `Proxy-Authorization` value, sent with the first request
+pub(crate) fn initial(proxy: &Proxy) -> Result<Option<Zeroizing<String>>, ConnectError> {
+    let value = match (&proxy.http.auth, &proxy.creds) {
+        (HttpAuth::Basic, Some((login, password))) => {
+            let plain = Zeroizing::new(format!("{}:{}", login, password.expose()));
+            let token = Zeroizing::new(base64::engine::general_purpose::STANDARD.encode(&*plain));
+            Some(Zeroizing::new(format!("Basic {}", &*token)))
+        }
+        (HttpAuth::Bearer(token), _) => Some(Zeroizing::new(format!("Bearer {}", token.expose()))),
+        (HttpAuth::Ntlm { .. }, Some(_)) => Some(Zeroizing::new(format!(
+            "NTLM {}",
+            base64::engine::general_purpose::STANDARD.encode(ntlm::negotiate())
+        ))),
+        _ => None,
+    };
+    value.map(validated).transpose()
+}
+
+/// `Proxy-Authorization` value, answering the challenge of `407` response
+///
+/// `None` if configured scheme doesn't answer challenges, or proxy hasn't offered it.
+pub(crate) fn answer(
+    proxy: &Proxy,
+    authority: &str,
+    response: &HttpResponse,
+) -> Result<Option<Zeroizing<String>>, ConnectError> {
+    let Some((login, password)) = &proxy.creds else {
+        return Ok(None);
+    };
+
+    match &proxy.http.auth {
+        HttpAuth::Digest => {
+            let Some(challenge) = challenge(response, "Digest") else {
+                return Ok(None);
+            };
+            digest(
+                &parse_params(challenge),
+                login,
+                password.expose(),
+                authority,
+            )
+            .and_then(validated)
+            .map(Some)
+        }
+        HttpAuth::Ntlm {
+            domain,
+            workstation,
+        } => {
+            let Some(challenge) = challenge(response, "NTLM").filter(|token| !token.is_empty())
+            else {
+                return Ok(None);
+            };
+            ntlm(
+                challenge,
+                login,
+                password.expose(),
+                domain.as_deref(),
+                workstation.as_deref().unwrap_or_default(),
+            )
+            .map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Rejects value, which would break out of `Proxy-Authorization` header,
+/// e.g. token or login with line breaks
+fn validated(value: Zeroizing<String>) -> Result<Zeroizing<String>, ConnectError> {
+    validate_header("Proxy-Authorization", &value)?;
+    Ok(value)
+}
+
+/// Parameters of the first `Proxy-Authenticate` challenge with given scheme
+pub(crate) fn challenge<'a>(response: &'a HttpResponse, scheme: &str) -> Option<&'a str> {
+    response
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Proxy-Authenticate"))
+        .flat_map(|(_, value)| split_challenges(value))
+        .find_map(|(name, params)| name.eq_ignore_ascii_case(scheme).then_some(params))
+}
+
+/// Splits header value into `(scheme, parameters)` of each challenge
+///
+/// Challenges and their parameters share the same comma separated list (RFC 9110, 11.6.1),
+/// so challenge starts at each list element, which isn't a `key=value` parameter.
+fn split_challenges(value: &str) -> Vec<(&str, &str)> {
+    // scheme, along with range of its parameters in `value`
+    let mut challenges: Vec<(&str, usize, usize)> = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (index, char) in value.char_indices().chain([(value.len(), ',')]) {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                let element = &value[start..index];
+                let offset = start + element.len() - element.trim_start().len();
+                let element = element.trim();
+                start = index + 1;
+
+                let (scheme, params) = element.split_once([' ', '\t']).unwrap_or((element, ""));
+                let param = scheme.contains('=') || params.trim_start().starts_with('=');
+                match challenges.last_mut() {
+                    _ if element.is_empty() => {}
+                    Some((_, _, end)) if param => *end = offset + element.len(),
+                    _ => challenges.push((scheme, offset + scheme.len(), offset + element.len())),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    challenges
+        .into_iter()
+        .map(|(scheme, start, end)| (scheme, value[start..end].trim()))
+        .collect()
+}
+
+/// Parses comma separated `key=value` pairs, where value may be a quoted string
+fn parse_params(input: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = input;
+
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        let Some((key, tail)) = rest.split_once('=') else {
+            break;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let tail = tail.trim_start();
+
+        let (value, tail) = match tail.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((index, char)) = chars.next() {
+                    match char {
+                        '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        char => value.push(char),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = tail.find(',').unwrap_or(tail.len());
+                (tail[..end].trim().to_owned(), &tail[end..])
+            }
+        };
+
+        params.push((key, value));
+        rest = tail;
+    }
+
+    params
+}
+
+/// Quoted string (RFC 9110), with quotes and backslashes escaped
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for char in value.chars() {
+        if matches!(char, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(char);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hash<D: Digest>(input: &str) -> String {
+    hex(&D::digest(input.as_bytes()))
+}
+
+/// Response to `Digest` challenge (RFC 7616), for `CONNECT` to `authority`
+fn digest(
+    params: &[(String, String)],
+    login: &str,
+    password: &str,
+    authority: &str,
+) -> Result<Zeroizing<String>, ConnectError> {
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let realm = param("realm").unwrap_or_default();
+    let nonce = param("nonce").ok_or(ConnectError::AuthFailed {
+        details: Some("digest challenge has no nonce".to_owned()),
+    })?;
+    let algorithm = param("algorithm").unwrap_or("MD5");
+    let qop = param("qop").map(|qop| qop.split(',').any(|qop| qop.trim() == "auth"));
+
+    let hash: fn(&str) -> String = match algorithm.to_ascii_uppercase().as_str() {
+        "MD5" | "MD5-SESS" => hash::<Md5>,
+        "SHA-256" | "SHA-256-SESS" => hash::<Sha256>,
+        _ => {
+            return Err(ConnectError::AuthFailed {
+                details: Some(format!("unsupported digest algorithm {}", algorithm)),
+            })
+        }
+    };
+
+    let cnonce = {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(|error| ConnectError::AuthFailed {
+            details: Some(format!("failed to generate cnonce: {}", error)),
+        })?;
+        hex(&bytes)
+    };
+    let nc = "00000001";
+
+    let mut ha1 = Zeroizing::new(hash(&Zeroizing::new(format!(
+        "{}:{}:{}",
+        login, realm, password
+    ))));
+    if algorithm.to_ascii_uppercase().ends_with("-SESS") {
+        ha1 = Zeroizing::new(hash(&format!("{}:{}:{}", &*ha1, nonce, cnonce)));
+    }
+    let ha2 = hash(&format!("CONNECT:{}", authority));
+
+    let response = match qop {
+        Some(true) => hash(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            &*ha1, nonce, nc, cnonce, ha2
+        )),
+        Some(false) => {
+            return Err(ConnectError::AuthFailed {
+                details: Some("digest challenge offers no supported qop".to_owned()),
+            })
+        }
+        None => hash(&format!("{}:{}:{}", &*ha1, nonce, ha2)),
+    };
+
+    let mut header = format!(
+        "Digest username={}, realm={}, nonce={}, uri={}, algorithm={}, response={}",
+        quote(login),
+        quote(realm),
+        quote(nonce),
+        quote(authority),
+        algorithm,
+        quote(&response)
+    );
+    if qop.is_some() {
+        header.push_str(&format!(", qop=auth, nc={}, cnonce={}", nc, quote(&cnonce)));
+    }
+    if let Some(opaque) = param("opaque") {
+        header.push_str(&format!(", opaque={}", quote(opaque)));
+    }
+
+    Ok(Zeroizing::new(header))
+}
+
+/// `AUTHENTICATE_MESSAGE` answering base64 encoded NTLM `challenge`
+fn ntlm(
+    challenge: &str,
+    login: &str,
+    password: &str,
+    domain: Option<&str>,
+    workstation: &str,
+) -> Result<Zeroizing<String>, ConnectError> {
+    let malformed = || ConnectError::AuthFailed {
+        details: Some("malformed NTLM challenge".to_owned()),
+    };
+
+    let challenge = base64::engine::general_purpose::STANDARD
+        .decode(challenge)
+        .ok()
+        .and_then(|message| ntlm::Challenge::parse(&message))
+        .ok_or_else(malformed)?;
+
+    let (login_domain, user) = login.split_once('\\').unwrap_or(("", login));
+    let creds = ntlm::Credentials {
+        user,
+        domain: domain.unwrap_or(login_domain),
+        workstation,
+        password,
+    };
+
+    let mut client_challenge = [0u8; 8];
+    getrandom::getrandom(&mut client_challenge).map_err(|error| ConnectError::AuthFailed {
+        details: Some(format!("failed to generate client challenge: {}", error)),
+    })?;
+
+    let message = ntlm::authenticate(
+        &challenge,
+        &creds,
+        client_challenge,
+        std::time::SystemTime::now(),
+    )
+    .ok_or_else(malformed)?;
+
+    Ok(Zeroizing::new(format!(
+        "NTLM {}",
+        base64::engine::general_purpose::STANDARD.encode(message)
+    )))
+}
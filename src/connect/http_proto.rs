@@ -0,0 +1,309 @@
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use zeroize::Zeroizing;
+
+use crate::{HttpResponse, Proxy};
+
+use super::{http_auth, ConnectError, Deadline, Negotiated, NetworkTarget, ProxyProto, Stage};
+
+/// Limit for status line and headers of `CONNECT` response
+const MAX_RESPONSE_HEAD: u64 = 16 * 1024;
+
+/// Parses status line into status code and reason phrase
+fn parse_status_line(line: &str) -> Option<(u16, String)> {
+    let mut parts = line.trim_end().splitn(3, ' ');
+
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let status = parts.next()?.parse().ok()?;
+    let reason = parts.next().unwrap_or_default().to_owned();
+
+    Some((status, reason))
+}
+
+/// Parses `Name: value` header line, malformed ones are ignored
+fn parse_header(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    Some((name.trim().to_owned(), value.trim().to_owned()))
+}
+
+/// Reads response status line and headers, leaving body in `reader`
+pub(crate) async fn read_response<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<HttpResponse, ConnectError> {
+    let mut reader = reader.take(MAX_RESPONSE_HEAD);
+    let mut response: Option<HttpResponse> = None;
+
+    loop {
+        let mut line = Vec::new();
+        reader
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(ConnectError::io(Stage::Request))?;
+
+        if !line.ends_with(b"\n") {
+            // either connection is closed, or proxy sends something, that isn't HTTP
+            return Err(match line.is_empty() {
+                true => ConnectError::IO {
+                    stage: Stage::Request,
+                    source: std::io::ErrorKind::UnexpectedEof.into(),
+                },
+                false => ConnectError::WrongProtocol {
+                    stage: Stage::Request,
+                },
+            });
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        match &mut response {
+            None => {
+                let (status, reason) =
+                    parse_status_line(&line).ok_or(ConnectError::WrongProtocol {
+                        stage: Stage::Request,
+                    })?;
+                response = Some(HttpResponse {
+                    status,
+                    reason,
+                    headers: Vec::new(),
+                });
+            }
+            Some(_) if line == "\r\n" || line == "\n" => break,
+            Some(response) => response.headers.extend(parse_header(&line)),
+        }
+    }
+
+    Ok(response.expect("loop exits only after status line is read"))
+}
+
+/// How the end of response body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyLength {
+    Fixed(u64),
+    Chunked,
+    /// Neither `Content-Length` nor chunked encoding, body lasts until connection is closed
+    UntilClose,
+}
+
+impl BodyLength {
+    pub(crate) fn of(response: &HttpResponse) -> Self {
+        let chunked = response
+            .header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Self::Chunked;
+        }
+
+        match response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok())
+        {
+            Some(length) => Self::Fixed(length),
+            None => Self::UntilClose,
+        }
+    }
+}
+
+/// Skips body of non-successful response, so connection can be reused for the next request
+///
+/// Body without `Content-Length` or chunked encoding lasts until connection is closed,
+/// so there is nothing to skip, as connection can't be reused anyway.
+async fn skip_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    response: &HttpResponse,
+) -> Result<(), ConnectError> {
+    let length = match BodyLength::of(response) {
+        BodyLength::UntilClose => BodyLength::Fixed(0),
+        length => length,
+    };
+    copy_body(reader, length, &mut tokio::io::sink()).await
+}
+
+/// Copies response body of given `length` from `reader` into `writer`, decoding chunks
+pub(crate) async fn copy_body<R, W>(
+    reader: &mut R,
+    length: BodyLength,
+    writer: &mut W,
+) -> Result<(), ConnectError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Fixed(length) => return copy_exact(reader, length, writer).await,
+        BodyLength::UntilClose => {
+            tokio::io::copy(reader, writer)
+                .await
+                .map_err(ConnectError::io(Stage::Request))?;
+            return Ok(());
+        }
+        BodyLength::Chunked => {}
+    }
+
+    loop {
+        let mut line = Vec::new();
+        (&mut *reader)
+            .take(MAX_RESPONSE_HEAD)
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(ConnectError::io(Stage::Request))?;
+
+        let line = String::from_utf8_lossy(&line);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| ConnectError::WrongProtocol {
+            stage: Stage::Request,
+        })?;
+
+        if size == 0 {
+            break;
+        }
+        copy_exact(reader, size, writer).await?;
+        // chunk data is followed by CRLF
+        copy_exact(reader, 2, &mut tokio::io::sink()).await?;
+    }
+
+    // trailers, up to the empty line
+    loop {
+        let mut line = Vec::new();
+        (&mut *reader)
+            .take(MAX_RESPONSE_HEAD)
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(ConnectError::io(Stage::Request))?;
+
+        if line.is_empty() || line == b"\r\n" || line == b"\n" {
+            return Ok(());
+        }
+    }
+}
+
+async fn copy_exact<R, W>(reader: &mut R, length: u64, writer: &mut W) -> Result<(), ConnectError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(&mut reader.take(length), writer)
+        .await
+        .map_err(ConnectError::io(Stage::Request))?;
+
+    match copied == length {
+        true => Ok(()),
+        false => Err(ConnectError::IO {
+            stage: Stage::Request,
+            source: std::io::ErrorKind::UnexpectedEof.into(),
+        }),
+    }
+}
+
+/// Rejects header, which would allow to smuggle extra requests
+pub(crate) fn validate_header(name: &str, value: &str) -> Result<(), ConnectError> {
+    let valid_name = !name.is_empty() && !name.contains([':', '\r', '\n']);
+    match valid_name && !value.contains(['\r', '\n']) {
+        true => Ok(()),
+        false => Err(ConnectError::InvalidHeader {
+            name: name.to_owned(),
+        }),
+    }
+}
+
+/// Serializes `CONNECT` request head
+fn write_request(
+    authority: &str,
+    headers: &[(String, String)],
+    authorization: Option<&str>,
+) -> Result<Zeroizing<String>, ConnectError> {
+    let mut request = Zeroizing::new(format!(
+        "CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n"
+    ));
+
+    for (name, value) in headers {
+        validate_header(name, value)?;
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    if let Some(authorization) = authorization {
+        request.push_str("Proxy-Authorization: ");
+        request.push_str(authorization);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+
+    Ok(request)
+}
+
+pub struct HttpProtocol;
+impl ProxyProto for HttpProtocol {
+    async fn new<S: AsyncRead + AsyncWrite + Unpin>(
+        proxy: &Proxy,
+        target: NetworkTarget,
+        proxy_stream: &mut S,
+        deadline: Deadline,
+    ) -> Result<Negotiated, ConnectError> {
+        let authority = target.to_string();
+        let mut authorization = http_auth::initial(proxy)?;
+        let mut challenged = false;
+        let mut reader = BufReader::new(proxy_stream);
+
+        loop {
+            let request = write_request(
+                &authority,
+                &proxy.http.headers,
+                authorization.as_ref().map(|value| value.as_str()),
+            )?;
+
+            deadline
+                .run(Stage::Reply, async {
+                    reader
+                        .get_mut()
+                        .write_all(request.as_bytes())
+                        .await
+                        .map_err(ConnectError::io(Stage::Request))?;
+
+                    reader
+                        .fill_buf()
+                        .await
+                        .map_err(ConnectError::io(Stage::Reply))
+                })
+                .await?;
+            let response = deadline
+                .run(Stage::Request, read_response(&mut reader))
+                .await?;
+
+            match response.status {
+                200..=299 => {
+                    return Ok(Negotiated {
+                        http_response: Some(response),
+                        // proxy may pipeline target data right after the response head
+                        leftover: reader.buffer().to_vec(),
+                        ..Default::default()
+                    });
+                }
+                407 if !challenged => {
+                    if let Some(answer) = http_auth::answer(proxy, &authority, &response)? {
+                        deadline
+                            .run(Stage::Auth, skip_body(&mut reader, &response))
+                            .await?;
+                        authorization = Some(answer);
+                        challenged = true;
+                        continue;
+                    }
+                    return Err(auth_failed(&response));
+                }
+                407 => return Err(auth_failed(&response)),
+                status => {
+                    return Err(ConnectError::Http {
+                        status,
+                        reason: response.reason,
+                    })
+                }
+            }
+        }
+    }
+}
+
+fn auth_failed(response: &HttpResponse) -> ConnectError {
+    ConnectError::AuthFailed {
+        details: Some(format!("{} {}", response.status, response.reason)),
+    }
+}
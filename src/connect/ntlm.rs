@@ -0,0 +1,215 @@
+//! NTLMv2 messages (MS-NLMP), only the parts needed to authenticate on a proxy
+
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use zeroize::Zeroizing;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+const AV_EOL: u16 = 0;
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601-01-01 (FILETIME epoch) and 1970-01-01
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// `NEGOTIATE_MESSAGE` without domain and workstation
+pub(crate) fn negotiate() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&FLAGS.to_le_bytes());
+    message.extend_from_slice(&[0u8; 16]);
+    message
+}
+
+/// Parsed `CHALLENGE_MESSAGE`
+pub(crate) struct Challenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    pub(crate) fn parse(message: &[u8]) -> Option<Self> {
+        if message.get(..8)? != SIGNATURE || read_u32(message, 8)? != 2 {
+            return None;
+        }
+
+        let flags = read_u32(message, 20)?;
+        let server_challenge = message.get(24..32)?.try_into().ok()?;
+        let target_info = match flags & NEGOTIATE_TARGET_INFO {
+            0 => Vec::new(),
+            _ => field(message, 40)?.to_vec(),
+        };
+
+        Some(Self {
+            flags,
+            server_challenge,
+            target_info,
+        })
+    }
+
+    /// Server time from `MsvAvTimestamp` pair of target info
+    fn timestamp(&self) -> Option<[u8; 8]> {
+        let mut pairs = self.target_info.as_slice();
+        while pairs.len() >= 4 {
+            let id = read_u16(pairs, 0)?;
+            let len = read_u16(pairs, 2)? as usize;
+            let value = pairs.get(4..4 + len)?;
+            match id {
+                AV_EOL => break,
+                AV_TIMESTAMP => return value.try_into().ok(),
+                _ => pairs = &pairs[4 + len..],
+            }
+        }
+        None
+    }
+}
+
+/// Credentials of `AUTHENTICATE_MESSAGE`
+pub(crate) struct Credentials<'a> {
+    pub user: &'a str,
+    pub domain: &'a str,
+    pub workstation: &'a str,
+    pub password: &'a str,
+}
+
+/// `AUTHENTICATE_MESSAGE` with NTLMv2 response
+///
+/// `now` is used as FILETIME when challenge doesn't carry a timestamp.
+/// `None` if any of the fields doesn't fit into the message.
+pub(crate) fn authenticate(
+    challenge: &Challenge,
+    creds: &Credentials,
+    client_challenge: [u8; 8],
+    now: std::time::SystemTime,
+) -> Option<Vec<u8>> {
+    let unicode = challenge.flags & NEGOTIATE_UNICODE != 0;
+    let encode = |value: &str| match unicode {
+        true => utf16le(value),
+        false => value.as_bytes().to_vec(),
+    };
+
+    let nt_hash = Zeroizing::new(<[u8; 16]>::from(Md4::digest(&*Zeroizing::new(utf16le(
+        creds.password,
+    )))));
+    let identity = [utf16le(&creds.user.to_uppercase()), utf16le(creds.domain)].concat();
+    let ntowf = Zeroizing::new(hmac_md5(&*nt_hash, &[&identity]));
+
+    let server_timestamp = challenge.timestamp();
+    let timestamp = server_timestamp.unwrap_or_else(|| filetime(now));
+
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp);
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let proof = hmac_md5(&*ntowf, &[&challenge.server_challenge, &blob]);
+    let nt_response = [&proof[..], &blob].concat();
+
+    // LMv2 is omitted when server provides timestamp (MS-NLMP 3.1.5.1.2)
+    let lm_response = match server_timestamp {
+        Some(_) => vec![0; 24],
+        None => {
+            let proof = hmac_md5(&*ntowf, &[&challenge.server_challenge, &client_challenge]);
+            [&proof[..], &client_challenge].concat()
+        }
+    };
+
+    let mut flags = challenge.flags & FLAGS;
+    flags &= if unicode {
+        !NEGOTIATE_OEM
+    } else {
+        !NEGOTIATE_UNICODE
+    };
+
+    let fields = [
+        lm_response,
+        nt_response,
+        encode(creds.domain),
+        encode(creds.user),
+        encode(creds.workstation),
+        Vec::new(),
+    ];
+
+    let mut message = Vec::new();
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&3u32.to_le_bytes());
+
+    let mut payload = Vec::new();
+    let mut offset = 64;
+    for field in &fields {
+        let len = u16::try_from(field.len()).ok()?;
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&u32::try_from(offset).ok()?.to_le_bytes());
+        payload.extend_from_slice(field);
+        offset += field.len();
+    }
+    message.extend_from_slice(&flags.to_le_bytes());
+    message.extend_from_slice(&payload);
+
+    Some(message)
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn utf16le(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn filetime(time: std::time::SystemTime) -> [u8; 8] {
+    let since_unix = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let ticks = (since_unix.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000
+        + u64::from(since_unix.subsec_nanos() / 100);
+    ticks.to_le_bytes()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Payload referenced by security buffer fields at `offset`
+fn field(message: &[u8], offset: usize) -> Option<&[u8]> {
+    let len = read_u16(message, offset)? as usize;
+    let start = read_u32(message, offset + 4)? as usize;
+    message.get(start..start + len)
+}
@@ -0,0 +1,105 @@
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Proxy, ProxyKind};
+
+use super::{ConnectError, Deadline, Negotiated, NetworkTarget, ProxyProto, Stage};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+
+const SOCKS4_REPLY_GRANTED: u8 = 0x5A;
+const SOCKS4_REPLY_REJECTED: u8 = 0x5B;
+const SOCKS4_REPLY_IDENTD_UNREACHABLE: u8 = 0x5C;
+const SOCKS4_REPLY_IDENTD_MISMATCH: u8 = 0x5D;
+
+/// Invalid `DSTIP` of form `0.0.0.x` (x != 0), which tells SOCKS4a server to resolve domain
+const SOCKS4A_DOMAIN_MARKER: [u8; 4] = [0, 0, 0, 1];
+
+/// Handles both SOCKS4 and SOCKS4a, difference is only in who resolves domain targets
+pub struct Socks4Protocol;
+impl ProxyProto for Socks4Protocol {
+    async fn new<S: AsyncRead + AsyncWrite + Unpin>(
+        proxy: &Proxy,
+        target: NetworkTarget,
+        proxy_stream: &mut S,
+        deadline: Deadline,
+    ) -> Result<Negotiated, ConnectError> {
+        let mut request = vec![SOCKS4_VERSION, SOCKS4_CMD_CONNECT];
+        request.extend_from_slice(&target.port().to_be_bytes());
+
+        let remote_domain = match target {
+            NetworkTarget::IPAddr {
+                socket: SocketAddr::V4(socket),
+            } => {
+                request.extend_from_slice(&socket.ip().octets());
+                None
+            }
+            NetworkTarget::IPAddr {
+                socket: SocketAddr::V6(_),
+            } => return Err(ConnectError::AddressTypeUnsupported),
+            NetworkTarget::Domain { domain, .. } if proxy.kind == ProxyKind::Socks4a => {
+                request.extend_from_slice(&SOCKS4A_DOMAIN_MARKER);
+                Some(domain)
+            }
+            // resolved beforehand by `resolve_target`
+            NetworkTarget::Domain { .. } => return Err(ConnectError::AddressTypeUnsupported),
+        };
+
+        // SOCKS4 has no passwords, only USERID field
+        if let Some((login, _)) = &proxy.creds {
+            request.extend_from_slice(login.as_bytes());
+        }
+        request.push(0);
+
+        if let Some(domain) = remote_domain {
+            request.extend_from_slice(domain.as_bytes());
+            request.push(0);
+        }
+
+        let mut reply = [0u8; 8];
+        deadline
+            .run(Stage::Reply, async {
+                proxy_stream
+                    .write_all(&request)
+                    .await
+                    .map_err(ConnectError::io(Stage::Request))?;
+
+                proxy_stream
+                    .read_exact(&mut reply[..1])
+                    .await
+                    .map_err(ConnectError::io(Stage::Reply))
+            })
+            .await?;
+        deadline
+            .run(Stage::Request, async {
+                proxy_stream
+                    .read_exact(&mut reply[1..])
+                    .await
+                    .map_err(ConnectError::io(Stage::Request))
+            })
+            .await?;
+
+        // reply version must be 0, although some servers answer with 4
+        if reply[0] != 0 && reply[0] != SOCKS4_VERSION {
+            return Err(ConnectError::WrongProtocol {
+                stage: Stage::Request,
+            });
+        }
+
+        match reply[1] {
+            SOCKS4_REPLY_GRANTED => Ok(Negotiated::default()),
+            SOCKS4_REPLY_REJECTED => Err(ConnectError::Socks4Rejected),
+            SOCKS4_REPLY_IDENTD_UNREACHABLE => Err(ConnectError::AuthFailed {
+                details: Some("proxy cannot connect to identd on the client".to_owned()),
+            }),
+            SOCKS4_REPLY_IDENTD_MISMATCH => Err(ConnectError::AuthFailed {
+                details: Some("identd reported a different user id".to_owned()),
+            }),
+            _ => Err(ConnectError::WrongProtocol {
+                stage: Stage::Request,
+            }),
+        }
+    }
+}
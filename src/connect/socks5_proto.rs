@@ -0,0 +1,318 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+use crate::Proxy;
+
+use super::{ConnectError, Deadline, Negotiated, NetworkTarget, ProxyProto, Stage};
+
+const SOCKS5_VERSION: u8 = 0x05;
+
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+
+/// Version of username/password sub-negotiation (RFC 1929)
+const SOCKS5_PASSWORD_VERSION: u8 = 0x01;
+
+pub(crate) const SOCKS5_CMD_CONNECT: u8 = 0x01;
+pub(crate) const SOCKS5_CMD_BIND: u8 = 0x02;
+pub(crate) const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 `REP` code of failed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Socks5Reply {
+    GeneralFailure,
+    ConnectionNotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    /// Code, not defined by RFC 1928
+    Unassigned(u8),
+}
+
+impl Socks5Reply {
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Self::GeneralFailure,
+            0x02 => Self::ConnectionNotAllowed,
+            0x03 => Self::NetworkUnreachable,
+            0x04 => Self::HostUnreachable,
+            0x05 => Self::ConnectionRefused,
+            0x06 => Self::TtlExpired,
+            0x07 => Self::CommandNotSupported,
+            0x08 => Self::AddressTypeNotSupported,
+            code => Self::Unassigned(code),
+        }
+    }
+
+    /// Raw `REP` field value
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::GeneralFailure => 0x01,
+            Self::ConnectionNotAllowed => 0x02,
+            Self::NetworkUnreachable => 0x03,
+            Self::HostUnreachable => 0x04,
+            Self::ConnectionRefused => 0x05,
+            Self::TtlExpired => 0x06,
+            Self::CommandNotSupported => 0x07,
+            Self::AddressTypeNotSupported => 0x08,
+            Self::Unassigned(code) => *code,
+        }
+    }
+}
+
+impl std::fmt::Display for Socks5Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::GeneralFailure => f.write_str("general SOCKS server failure"),
+            Self::ConnectionNotAllowed => f.write_str("connection not allowed by ruleset"),
+            Self::NetworkUnreachable => f.write_str("network unreachable"),
+            Self::HostUnreachable => f.write_str("host unreachable"),
+            Self::ConnectionRefused => f.write_str("connection refused"),
+            Self::TtlExpired => f.write_str("TTL expired"),
+            Self::CommandNotSupported => f.write_str("command not supported"),
+            Self::AddressTypeNotSupported => f.write_str("address type not supported"),
+            Self::Unassigned(code) => f.write_fmt(format_args!("unassigned reply {:#04x}", code)),
+        }
+    }
+}
+
+/// Version identifier/method selection and optional username/password authentication
+pub(crate) async fn greet<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    stream: &mut S,
+    deadline: Deadline,
+) -> Result<(), ConnectError> {
+    let method = deadline
+        .run(Stage::Greeting, async {
+            let greeting: &[u8] = match &proxy.creds {
+                Some(_) => &[SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD],
+                None => &[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE],
+            };
+            stream
+                .write_all(greeting)
+                .await
+                .map_err(ConnectError::io(Stage::Greeting))?;
+
+            let mut reply = [0u8; 2];
+            stream
+                .read_exact(&mut reply)
+                .await
+                .map_err(ConnectError::io(Stage::Greeting))?;
+
+            if reply[0] != SOCKS5_VERSION {
+                return Err(ConnectError::WrongProtocol {
+                    stage: Stage::Greeting,
+                });
+            }
+
+            Ok(reply[1])
+        })
+        .await?;
+
+    match (method, &proxy.creds) {
+        (SOCKS5_AUTH_NONE, _) => Ok(()),
+        (SOCKS5_AUTH_PASSWORD, Some((username, password))) => {
+            deadline
+                .run(
+                    Stage::Auth,
+                    authenticate(stream, username, password.expose()),
+                )
+                .await
+        }
+        _ => Err(ConnectError::AuthMethodUnacceptable),
+    }
+}
+
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    username: &str,
+    password: &str,
+) -> Result<(), ConnectError> {
+    let (username, password) = (username.as_bytes(), password.as_bytes());
+    if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+        return Err(ConnectError::AuthFailed {
+            details: Some("username and password are limited to 255 bytes".to_owned()),
+        });
+    }
+
+    let mut request = Zeroizing::new(vec![SOCKS5_PASSWORD_VERSION, username.len() as u8]);
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+
+    stream
+        .write_all(&request)
+        .await
+        .map_err(ConnectError::io(Stage::Auth))?;
+
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(ConnectError::io(Stage::Auth))?;
+
+    match reply[1] {
+        0 => Ok(()),
+        status => Err(ConnectError::AuthFailed {
+            details: Some(format!("proxy rejected credentials with status {}", status)),
+        }),
+    }
+}
+
+/// Appends `ATYP`, `ADDR` and `PORT` fields of `target`
+pub(crate) fn encode_target(
+    target: &NetworkTarget,
+    packet: &mut Vec<u8>,
+) -> Result<(), ConnectError> {
+    match target {
+        NetworkTarget::IPAddr {
+            socket: SocketAddr::V4(socket),
+        } => {
+            packet.push(SOCKS5_ATYP_IPV4);
+            packet.extend_from_slice(&socket.ip().octets());
+        }
+        NetworkTarget::IPAddr {
+            socket: SocketAddr::V6(socket),
+        } => {
+            packet.push(SOCKS5_ATYP_IPV6);
+            packet.extend_from_slice(&socket.ip().octets());
+        }
+        NetworkTarget::Domain { domain, .. } => {
+            if domain.len() > u8::MAX as usize {
+                return Err(ConnectError::ExceededMaxDomainLen);
+            }
+            packet.push(SOCKS5_ATYP_DOMAIN);
+            packet.push(domain.len() as u8);
+            packet.extend_from_slice(domain.as_bytes());
+        }
+    }
+    packet.extend_from_slice(&target.port().to_be_bytes());
+
+    Ok(())
+}
+
+/// Reads `ADDR` and `PORT` fields of type `atyp`
+pub(crate) async fn read_target<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    atyp: u8,
+    stage: Stage,
+) -> Result<NetworkTarget, ConnectError> {
+    let target = match atyp {
+        SOCKS5_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream
+                .read_exact(&mut ip)
+                .await
+                .map_err(ConnectError::io(stage))?;
+            let port = stream.read_u16().await.map_err(ConnectError::io(stage))?;
+
+            NetworkTarget::IPAddr {
+                socket: SocketAddrV4::new(Ipv4Addr::from(ip), port).into(),
+            }
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream
+                .read_exact(&mut ip)
+                .await
+                .map_err(ConnectError::io(stage))?;
+            let port = stream.read_u16().await.map_err(ConnectError::io(stage))?;
+
+            NetworkTarget::IPAddr {
+                socket: SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0).into(),
+            }
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let len = stream.read_u8().await.map_err(ConnectError::io(stage))?;
+            let mut domain = vec![0u8; len as usize];
+            stream
+                .read_exact(&mut domain)
+                .await
+                .map_err(ConnectError::io(stage))?;
+            let port = stream.read_u16().await.map_err(ConnectError::io(stage))?;
+
+            NetworkTarget::Domain {
+                domain: String::from_utf8_lossy(&domain).into_owned(),
+                port,
+            }
+        }
+        _ => return Err(ConnectError::WrongProtocol { stage }),
+    };
+
+    Ok(target)
+}
+
+/// Reads reply to the request, returning `BND.ADDR` and `BND.PORT`
+pub(crate) async fn read_reply<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<NetworkTarget, ConnectError> {
+    let mut header = [0u8; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(ConnectError::io(Stage::Request))?;
+
+    if header[0] != SOCKS5_VERSION {
+        return Err(ConnectError::WrongProtocol {
+            stage: Stage::Request,
+        });
+    }
+
+    if header[1] != 0 {
+        return Err(ConnectError::Socks5 {
+            reply: Socks5Reply::from_code(header[1]),
+        });
+    }
+
+    read_target(stream, header[3], Stage::Request).await
+}
+
+/// Sends `command` request for `target`, returning bound address from the reply
+pub(crate) async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    command: u8,
+    target: &NetworkTarget,
+) -> Result<NetworkTarget, ConnectError> {
+    let mut request = vec![SOCKS5_VERSION, command, 0x00];
+    encode_target(target, &mut request)?;
+
+    stream
+        .write_all(&request)
+        .await
+        .map_err(ConnectError::io(Stage::Request))?;
+
+    read_reply(stream).await
+}
+
+pub struct Socks5Protocol;
+impl ProxyProto for Socks5Protocol {
+    async fn new<S: AsyncRead + AsyncWrite + Unpin>(
+        proxy: &Proxy,
+        target: NetworkTarget,
+        proxy_stream: &mut S,
+        deadline: Deadline,
+    ) -> Result<Negotiated, ConnectError> {
+        greet(proxy, proxy_stream, deadline).await?;
+
+        let bound_addr = deadline
+            .run(
+                Stage::Request,
+                request(proxy_stream, SOCKS5_CMD_CONNECT, &target),
+            )
+            .await?;
+
+        Ok(Negotiated {
+            bound_addr: Some(bound_addr),
+            ..Default::default()
+        })
+    }
+}
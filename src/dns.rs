@@ -0,0 +1,561 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{AddrFamily, ConnectError, Stage};
+
+/// Future of [`Resolve::resolve`]
+pub type Resolving<'a> = Pin<Box<dyn Future<Output = std::io::Result<Resolved>> + Send + 'a>>;
+
+/// Answer of [`Resolve`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resolved {
+    /// Addresses in order of preference, empty if domain doesn't exist or has no records
+    pub addrs: Vec<IpAddr>,
+    /// How long the answer stays valid, `None` if resolver doesn't know it
+    pub ttl: Option<Duration>,
+}
+
+impl From<Vec<IpAddr>> for Resolved {
+    fn from(addrs: Vec<IpAddr>) -> Self {
+        Self { addrs, ttl: None }
+    }
+}
+
+/**
+DNS resolution backend, used for proxy addresses and for targets with local [`DnsMode`](crate::DnsMode)
+
+Injected through [`ConnectOptions::resolver`](crate::ConnectOptions::resolver).
+Implementations only look names up, caching and round-robin across returned
+addresses are done by [`Resolver`]. Nonexistent domain should be reported as empty
+[`Resolved`] rather than error, so it gets into negative cache.
+
+```rust
+use std::net::IpAddr;
+use proxied::{Resolve, Resolving};
+
+/// Resolves every name to loopback
+#[derive(Debug)]
+struct Loopback;
+
+impl Resolve for Loopback {
+    fn resolve<'a>(&'a self, _domain: &'a str) -> Resolving<'a> {
+        Box::pin(async { Ok(vec![IpAddr::from([127, 0, 0, 1])].into()) })
+    }
+}
+```
+*/
+pub trait Resolve: Send + Sync {
+    /// Addresses of `domain`, along with TTL if it is known
+    fn resolve<'a>(&'a self, domain: &'a str) -> Resolving<'a>;
+}
+
+/// Resolver of the operating system (`getaddrinfo` on a blocking thread pool)
+///
+/// TTL isn't reported by the system, so [`DnsCacheConfig::default_ttl`] is used.
+/// Domain, which system reports as unknown or having no addresses, gets empty [`Resolved`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> Resolving<'a> {
+        Box::pin(async move {
+            match tokio::net::lookup_host((domain, 0)).await {
+                Ok(addrs) => Ok(addrs.map(|addr| addr.ip()).collect::<Vec<_>>().into()),
+                Err(error) if is_not_found(&error) => Ok(Resolved::default()),
+                Err(error) => Err(error),
+            }
+        })
+    }
+}
+
+/// Whether `getaddrinfo` failed because domain doesn't exist (`EAI_NONAME`, `EAI_NODATA`),
+/// rather than because of resolver failure (e.g. `EAI_AGAIN`)
+///
+/// Standard library reports these codes only as a message of the error, except on Windows.
+fn is_not_found(error: &std::io::Error) -> bool {
+    // WSAHOST_NOT_FOUND and WSANO_DATA
+    if cfg!(windows) {
+        return matches!(error.raw_os_error(), Some(11001 | 11004));
+    }
+
+    let message = error.to_string();
+    [
+        // glibc
+        "Name or service not known",
+        "No address associated with hostname",
+        // BSD and macOS
+        "nodename nor servname provided, or not known",
+        "no address associated with nodename",
+        // musl
+        "Name does not resolve",
+        "Address not available",
+    ]
+    .iter()
+    .any(|known| message.contains(known))
+}
+
+/**
+Fixed map of hosts, e.g. to stub DNS in tests
+
+Domains are matched case insensitively, unknown ones have no addresses.
+
+```rust
+use proxied::{ConnectOptions, Resolver, StaticResolver};
+
+let hosts = StaticResolver::new()
+    .with_host("proxy.internal", ["10.0.0.1".parse().unwrap()])
+    .with_host("target.internal", ["10.0.0.2".parse().unwrap(), "::2".parse().unwrap()]);
+
+let options = ConnectOptions {
+    resolver: Resolver::new(hosts),
+    ..Default::default()
+};
+```
+*/
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `addrs` to the ones of `domain`
+    pub fn with_host(
+        mut self,
+        domain: impl AsRef<str>,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> Self {
+        self.hosts
+            .entry(domain.as_ref().to_ascii_lowercase())
+            .or_default()
+            .extend(addrs);
+        self
+    }
+}
+
+impl Resolve for StaticResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> Resolving<'a> {
+        let addrs = self.hosts.get(&domain.to_ascii_lowercase()).cloned();
+        Box::pin(async move { Ok(addrs.unwrap_or_default().into()) })
+    }
+}
+
+/**
+Asynchronous resolver of [`hickory_resolver`], avoiding blocking `getaddrinfo` threads
+
+Supports DNS over TLS and HTTPS:
+```rust,no_run
+use proxied::{HickoryResolver, hickory_resolver::config::ResolverConfig};
+
+let doh = HickoryResolver::with_config(ResolverConfig::cloudflare_https());
+let dot = HickoryResolver::with_config(ResolverConfig::quad9_tls());
+```
+*/
+#[cfg(feature = "hickory")]
+#[derive(Debug, Clone)]
+pub struct HickoryResolver(hickory_resolver::TokioResolver);
+
+#[cfg(feature = "hickory")]
+impl HickoryResolver {
+    /// Uses system configuration (`/etc/resolv.conf` on Unix)
+    pub fn system() -> std::io::Result<Self> {
+        let builder = hickory_resolver::Resolver::builder_tokio().map_err(std::io::Error::other)?;
+        Ok(Self(builder.build()))
+    }
+
+    pub fn with_config(config: hickory_resolver::config::ResolverConfig) -> Self {
+        let provider = hickory_resolver::name_server::TokioConnectionProvider::default();
+        Self(hickory_resolver::Resolver::builder_with_config(config, provider).build())
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl From<hickory_resolver::TokioResolver> for HickoryResolver {
+    fn from(resolver: hickory_resolver::TokioResolver) -> Self {
+        Self(resolver)
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl Resolve for HickoryResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> Resolving<'a> {
+        Box::pin(async move {
+            match self.0.lookup_ip(domain).await {
+                Ok(lookup) => Ok(Resolved {
+                    addrs: lookup.iter().collect(),
+                    ttl: Some(
+                        lookup
+                            .valid_until()
+                            .saturating_duration_since(std::time::Instant::now()),
+                    ),
+                }),
+                Err(error) if error.is_no_records_found() => Ok(Resolved::default()),
+                Err(error) => Err(std::io::Error::other(error)),
+            }
+        })
+    }
+}
+
+/// Settings of [`Resolver`] cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnsCacheConfig {
+    /// Limit of cached domains, least recently used ones are evicted first
+    pub capacity: usize,
+    /// TTL of answers, which don't carry one
+    pub default_ttl: Duration,
+    /// Lower bound of TTL, so domains with zero TTL aren't resolved on each connection
+    pub min_ttl: Duration,
+    /// Upper bound of TTL
+    pub max_ttl: Duration,
+    /// TTL of empty answers (e.g. `NXDOMAIN`), errors of resolver are never cached
+    pub negative_ttl: Duration,
+    /// How long address, which proxy connection failed at, is tried only after the others
+    pub dead_cooldown: Duration,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1_000,
+            default_ttl: Duration::from_secs(60),
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(60 * 60),
+            negative_ttl: Duration::from_secs(5),
+            dead_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Cached answer, see [`Resolver::cached`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub domain: String,
+    /// Empty for negative entries
+    pub addrs: Vec<IpAddr>,
+    pub expires_in: Duration,
+}
+
+struct AddrRecord {
+    items: Vec<IpAddr>,
+    next_item: usize,
+    expires: Instant,
+    /// Tick of the last use, key in [`DnsCache::recency`]
+    used: u64,
+}
+
+struct DnsCache {
+    config: DnsCacheConfig,
+    records: HashMap<String, AddrRecord>,
+    /// Domains by the tick of their last use, least recent first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    /// Addresses which failed to connect, along with the end of their cooldown
+    dead: HashMap<IpAddr, Instant>,
+}
+
+impl DnsCache {
+    fn new(config: DnsCacheConfig) -> Self {
+        Self {
+            config,
+            records: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            dead: HashMap::new(),
+        }
+    }
+
+    /// Addresses in cooldown, expired ones are forgotten
+    fn dead(&mut self, now: Instant) -> Vec<IpAddr> {
+        self.dead.retain(|_, until| *until > now);
+        self.dead.keys().copied().collect()
+    }
+
+    /// Live record of `domain`, marked as the most recently used one
+    fn get(&mut self, domain: &str, now: Instant) -> Option<&mut AddrRecord> {
+        let expired = self.records.get(domain)?.expires <= now;
+        if expired {
+            self.remove(domain);
+            return None;
+        }
+
+        self.tick += 1;
+        let record = self.records.get_mut(domain)?;
+        let name = self.recency.remove(&record.used)?;
+        record.used = self.tick;
+        self.recency.insert(self.tick, name);
+        Some(record)
+    }
+
+    fn insert(&mut self, domain: &str, resolved: Resolved, now: Instant) -> &mut AddrRecord {
+        let config = &self.config;
+        let ttl = match (resolved.addrs.is_empty(), resolved.ttl) {
+            (true, _) => config.negative_ttl,
+            (false, ttl) => ttl
+                .unwrap_or(config.default_ttl)
+                .clamp(config.min_ttl, config.max_ttl.max(config.min_ttl)),
+        };
+
+        self.remove(domain);
+        while self.records.len() >= self.config.capacity.max(1) {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.records.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.recency.insert(self.tick, domain.to_owned());
+        self.records
+            .entry(domain.to_owned())
+            .insert_entry(AddrRecord {
+                items: resolved.addrs,
+                next_item: 0,
+                expires: now + ttl,
+                used: self.tick,
+            })
+            .into_mut()
+    }
+
+    fn remove(&mut self, domain: &str) -> bool {
+        match self.records.remove(domain) {
+            Some(record) => {
+                self.recency.remove(&record.used);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Shared by every [`Resolver::default`], so round-robin works across connections
+static SYSTEM: LazyLock<Resolver> = LazyLock::new(|| Resolver::new(SystemResolver));
+
+/**
+[`Resolve`] implementation along with cache of its answers
+
+Each domain is resolved once per TTL, then connections round-robin across its addresses.
+Nonexistent domains are cached too, for a shorter time (see [`DnsCacheConfig`]).
+Clones share the cache, while [`Resolver::default`] is [`SystemResolver`] with process-wide cache.
+*/
+#[derive(Clone)]
+pub struct Resolver {
+    resolve: Arc<dyn Resolve>,
+    cache: Arc<Mutex<DnsCache>>,
+}
+
+impl Resolver {
+    pub fn new(resolve: impl Resolve + 'static) -> Self {
+        Self::with_cache(resolve, DnsCacheConfig::default())
+    }
+
+    pub fn with_cache(resolve: impl Resolve + 'static, config: DnsCacheConfig) -> Self {
+        Self {
+            resolve: Arc::new(resolve),
+            cache: Arc::new(Mutex::new(DnsCache::new(config))),
+        }
+    }
+
+    /// Live entries of the cache, least recently used first
+    pub fn cached(&self) -> Vec<CacheEntry> {
+        let cache = self.lock();
+        let now = Instant::now();
+
+        cache
+            .recency
+            .values()
+            .filter_map(|domain| {
+                let record = &cache.records[domain];
+                (record.expires > now).then(|| CacheEntry {
+                    domain: domain.clone(),
+                    addrs: record.items.clone(),
+                    expires_in: record.expires - now,
+                })
+            })
+            .collect()
+    }
+
+    /// Forgets cached answer for `domain`, returns whether there was one
+    pub fn flush_domain(&self, domain: &str) -> bool {
+        self.lock().remove(domain)
+    }
+
+    /// Forgets every cached answer
+    pub fn flush(&self) {
+        let mut cache = self.lock();
+        cache.records.clear();
+        cache.recency.clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DnsCache> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Next address of `domain` in round-robin order
+    pub(crate) async fn lookup(
+        &self,
+        domain: &str,
+        port: u16,
+        stage: Stage,
+    ) -> Result<SocketAddr, ConnectError> {
+        self.lookup_filtered(domain, port, stage, |_| true).await
+    }
+
+    /// Same as [`Resolver::lookup`], but skips addresses which don't match `filter`
+    pub(crate) async fn lookup_filtered(
+        &self,
+        domain: &str,
+        port: u16,
+        stage: Stage,
+        filter: fn(&IpAddr) -> bool,
+    ) -> Result<SocketAddr, ConnectError> {
+        let ips = self.addrs_of(domain, stage, filter, None).await?;
+        match ips.first() {
+            Some(ip) => Ok(SocketAddr::new(*ip, port)),
+            None => Err(ConnectError::DnsNameNotResolved { stage }),
+        }
+    }
+
+    /// Every address of `domain` to fail over across, starting from the next one
+    /// in round-robin order and alternating families, addresses in cooldown go last
+    pub(crate) async fn lookup_all(
+        &self,
+        domain: &str,
+        port: u16,
+        stage: Stage,
+        preferred: AddrFamily,
+    ) -> Result<Vec<SocketAddr>, ConnectError> {
+        let ips = self
+            .addrs_of(domain, stage, |_| true, Some(preferred))
+            .await?;
+        if ips.is_empty() {
+            return Err(ConnectError::DnsNameNotResolved { stage });
+        }
+
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Puts `ip` into cooldown after failed connection
+    pub(crate) fn mark_dead(&self, ip: IpAddr) {
+        let mut cache = self.lock();
+        let until = Instant::now() + cache.config.dead_cooldown;
+        cache.dead.insert(ip, until);
+    }
+
+    pub(crate) fn mark_alive(&self, ip: IpAddr) {
+        self.lock().dead.remove(&ip);
+    }
+
+    /// Addresses of `domain` from cache or resolved ones, ordered by [`name_present_dns`]
+    async fn addrs_of(
+        &self,
+        domain: &str,
+        stage: Stage,
+        filter: fn(&IpAddr) -> bool,
+        preferred: Option<AddrFamily>,
+    ) -> Result<Vec<IpAddr>, ConnectError> {
+        {
+            let mut cache = self.lock();
+            let now = Instant::now();
+            let dead = cache.dead(now);
+            if let Some(record) = cache.get(domain, now) {
+                return Ok(name_present_dns(record, filter, &dead, preferred));
+            }
+        }
+
+        // lock is free while resolving, so other connections aren't blocked
+        let resolved = self
+            .resolve
+            .resolve(domain)
+            .await
+            .map_err(ConnectError::io(stage))?;
+
+        let mut cache = self.lock();
+        let now = Instant::now();
+        let dead = cache.dead(now);
+
+        // other connection may have resolved it in the meantime and already moved
+        // round-robin forward, so its record is kept
+        if let Some(record) = cache.get(domain, now) {
+            return Ok(name_present_dns(record, filter, &dead, preferred));
+        }
+
+        // fresh answer is used even if zero TTL has already expired it
+        let record = cache.insert(domain, resolved, now);
+        Ok(name_present_dns(record, filter, &dead, preferred))
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        SYSTEM.clone()
+    }
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver").finish_non_exhaustive()
+    }
+}
+
+/// Addresses matching `filter` in round-robin order, `dead` ones go last
+///
+/// With `preferred` family, addresses alternate between families starting with it.
+/// Round-robin moves past the first returned address.
+fn name_present_dns(
+    record: &mut AddrRecord,
+    filter: fn(&IpAddr) -> bool,
+    dead: &[IpAddr],
+    preferred: Option<AddrFamily>,
+) -> Vec<IpAddr> {
+    let len = record.items.len();
+    let items = &record.items;
+    let (live, cooling): (Vec<usize>, Vec<usize>) = (0..len)
+        .map(|offset| (record.next_item + offset) % len)
+        .filter(|&index| filter(&items[index]))
+        .partition(|&index| !dead.contains(&items[index]));
+
+    let order: Vec<usize> = match preferred {
+        Some(preferred) => [live, cooling]
+            .into_iter()
+            .flat_map(|group| interleave(group, items, preferred))
+            .collect(),
+        None => live.into_iter().chain(cooling).collect(),
+    };
+    if let Some(first) = order.first() {
+        record.next_item = (first + 1) % len;
+    }
+
+    order.into_iter().map(|index| record.items[index]).collect()
+}
+
+/// Alternates address families of `group`, starting with `preferred` one
+fn interleave(group: Vec<usize>, items: &[IpAddr], preferred: AddrFamily) -> Vec<usize> {
+    let (first, second): (Vec<usize>, Vec<usize>) = group
+        .into_iter()
+        .partition(|&index| AddrFamily::of(&items[index]) == preferred);
+
+    let mut order = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return order,
+            (left, right) => order.extend(left.into_iter().chain(right)),
+        }
+    }
+}
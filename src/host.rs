@@ -0,0 +1,135 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::parse::ParseError;
+
+/**
+Address of the proxy server
+
+Kind of address is determined once, while parsing. Domains are validated (STD3 characters
+and underscore, no leading or trailing hyphens, DNS length limits) and stored in ASCII form
+(internationalized names are converted to punycode).
+
+Serialized as a plain string, just like `addr` used to be:
+```rust
+use proxied::ProxyHost;
+
+let host: ProxyHost = "Bücher.example".parse().unwrap();
+assert_eq!(host, ProxyHost::Domain("xn--bcher-kva.example".to_string()));
+assert_eq!("::1".parse::<ProxyHost>().unwrap().to_string(), "::1");
+```
+*/
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum ProxyHost {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Domain(String),
+}
+
+impl ProxyHost {
+    /// IP address, if host is not a domain
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            Self::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            Self::Domain(_) => None,
+        }
+    }
+
+    pub fn is_domain(&self) -> bool {
+        matches!(self, Self::Domain(_))
+    }
+}
+
+/// STD3 rules, relaxed to allow underscores, which real host names do contain
+const DENIED_ASCII: idna::uts46::AsciiDenyList =
+    idna::uts46::AsciiDenyList::new(true, "!\"#$%&'()*+,/:;<=>?@[\\]^`{|}~");
+
+impl FromStr for ProxyHost {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let unbracketed = input
+            .strip_prefix('[')
+            .and_then(|input| input.strip_suffix(']'));
+
+        if let Some(ip) = unbracketed {
+            return Ok(Self::Ipv6(ip.parse().map_err(|_| ParseError::InvalidHost)?));
+        }
+
+        if let Ok(ip) = input.parse::<IpAddr>() {
+            return Ok(ip.into());
+        }
+
+        // looks like IPv4 address, but isn't a valid one (e.g. `999.1.1.1` or `1.2.3`)
+        let numeric = input
+            .split('.')
+            .all(|label| !label.is_empty() && label.bytes().all(|byte| byte.is_ascii_digit()));
+        if numeric {
+            return Err(ParseError::InvalidHost);
+        }
+
+        let domain = idna::uts46::Uts46::new()
+            .to_ascii(
+                input.as_bytes(),
+                DENIED_ASCII,
+                idna::uts46::Hyphens::CheckFirstLast,
+                idna::uts46::DnsLength::VerifyAllowRootDot,
+            )
+            .map_err(|_| ParseError::InvalidHost)?;
+
+        if domain.is_empty() {
+            return Err(ParseError::InvalidHost);
+        }
+
+        Ok(Self::Domain(domain.into_owned()))
+    }
+}
+
+impl TryFrom<String> for ProxyHost {
+    type Error = ParseError;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl From<ProxyHost> for String {
+    fn from(host: ProxyHost) -> Self {
+        host.to_string()
+    }
+}
+
+impl From<IpAddr> for ProxyHost {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self::Ipv4(ip),
+            IpAddr::V6(ip) => Self::Ipv6(ip),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for ProxyHost {
+    fn from(ip: Ipv4Addr) -> Self {
+        Self::Ipv4(ip)
+    }
+}
+
+impl From<Ipv6Addr> for ProxyHost {
+    fn from(ip: Ipv6Addr) -> Self {
+        Self::Ipv6(ip)
+    }
+}
+
+impl std::fmt::Display for ProxyHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ipv4(ip) => ip.fmt(f),
+            Self::Ipv6(ip) => ip.fmt(f),
+            Self::Domain(domain) => f.write_str(domain),
+        }
+    }
+}
@@ -1,13 +1,40 @@
-use std::str::FromStr;
-
-use crate::{Proxy, ProxyKind};
+use std::{net::Ipv6Addr, str::FromStr};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::{Proxy, ProxyHost, ProxyKind, Secret};
+
+/// Characters, escaped in userinfo of the URL (WHATWG userinfo set, plus `%` itself)
+const USERINFO: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b':')
+    .add(b';')
+    .add(b'<')
+    .add(b'=')
+    .add(b'>')
+    .add(b'?')
+    .add(b'@')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
 
 impl FromStr for ProxyKind {
     type Err = ParseError;
     fn from_str(input: &str) -> Result<Self, Self::Err> {
         match input.to_ascii_lowercase().as_str() {
             "socks5" => Ok(Self::Socks5),
+            "socks5h" => Ok(Self::Socks5h),
             "socks4" => Ok(Self::Socks4),
+            "socks4a" => Ok(Self::Socks4a),
             "http" => Ok(Self::Http),
             "https" => Ok(Self::Https),
 
@@ -20,26 +47,77 @@ impl std::fmt::Display for ProxyKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         f.write_str(match self {
             Self::Socks4 => "socks4",
+            Self::Socks4a => "socks4a",
             Self::Socks5 => "socks5",
+            Self::Socks5h => "socks5h",
             Self::Http => "http",
             Self::Https => "https",
         })
     }
 }
 
-impl std::fmt::Display for Proxy {
+/// Host in form, suitable for URL authority (IPv6 is enclosed in brackets)
+pub(crate) struct UrlHost<'a>(pub(crate) &'a ProxyHost);
+
+impl std::fmt::Display for UrlHost<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self.0 {
+            ProxyHost::Ipv6(ip) => f.write_fmt(format_args!("[{}]", ip)),
+            host => host.fmt(f),
+        }
+    }
+}
+
+impl Proxy {
+    /**
+    Full proxy URL, including password
+
+    Credentials are percent-encoded, so the output is always parsed back to the same [`Proxy`].
+    Unlike `Display`, which hides the password, this should never end up in logs.
+    */
+    pub fn to_url_with_secrets(&self) -> String {
+        let mut url = String::new();
+        self.write_url(&mut url, true)
+            .expect("writing to String never fails");
+        url
+    }
+
+    fn write_url(&self, f: &mut impl std::fmt::Write, reveal: bool) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}://", self.kind))?;
+
         match &self.creds {
-            Some((login, password)) => f.write_fmt(format_args!(
-                "{}://{}:{}@{}:{}",
-                self.kind, login, password, self.addr, self.port,
-            ))?,
-            None => f.write_fmt(format_args!("{}://{}:{}", self.kind, self.addr, self.port))?,
+            Some((login, password)) if password.is_empty() => {
+                f.write_fmt(format_args!("{}@", utf8_percent_encode(login, USERINFO)))?
+            }
+            Some((login, password)) => {
+                f.write_fmt(format_args!("{}:", utf8_percent_encode(login, USERINFO)))?;
+                match reveal {
+                    true => f.write_fmt(format_args!(
+                        "{}@",
+                        utf8_percent_encode(password.expose(), USERINFO)
+                    ))?,
+                    false => f.write_str("***@")?,
+                }
+            }
+            None => {}
         };
+
+        f.write_fmt(format_args!("{}:{}", UrlHost(&self.addr), self.port))?;
+
+        if let Some(refresh_url) = &self.refresh_url {
+            f.write_fmt(format_args!("[{}]", refresh_url))?;
+        }
         Ok(())
     }
 }
 
+/// Proxy URL with password replaced by `***`, see [`Proxy::to_url_with_secrets`]
+impl std::fmt::Display for Proxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.write_url(f, false)
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ParseError {
     #[error("Verify correctness on proxy parts")]
@@ -53,51 +131,89 @@ pub enum ParseError {
 
     #[error("Failed to parse `refresh_url`")]
     InvalidRefresh,
+
+    #[error("Failed to parse host")]
+    InvalidHost,
+
+    #[error("Failed to decode credentials")]
+    InvalidCreds,
 }
-impl FromStr for Proxy {
-    type Err = ParseError;
 
-    fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let mut input = input;
-        let refresh_url = if input.ends_with(']') {
-            let begginning_index = input.rfind('[').ok_or(ParseError::InvalidRefresh)?;
-            let (rest, refresh) = input.split_at(begginning_index);
-            input = rest;
-            Some(refresh.trim_start_matches('[').to_owned())
-        } else {
-            None
-        };
+/// Splits `host:port`, where host may be IPv6 in brackets: `[2001:db8::1]:1080`
+fn split_host_port(input: &str) -> Result<(&str, &str), ParseError> {
+    if let Some(bracketed) = input.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']').ok_or(ParseError::InvalidHost)?;
+        host.parse::<Ipv6Addr>()
+            .map_err(|_| ParseError::InvalidHost)?;
+        let port = rest
+            .strip_prefix(':')
+            .ok_or(ParseError::InvalidChunkCount)?;
+
+        return Ok((host, port));
+    }
 
-        let mut input_stack = input.split(&[':', '@']).collect::<Vec<_>>();
+    match input.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Ok((host, port)),
+        _ => Err(ParseError::InvalidChunkCount),
+    }
+}
 
-        if !([3, 4, 5, 6].contains(&input_stack.len())) {
-            return Err(ParseError::InvalidChunkCount);
-        }
+/// Splits trailing `[refresh_url]`, which directly follows the port
+fn split_refresh(input: &str) -> Result<(&str, Option<&str>), ParseError> {
+    let Some(inner) = input.strip_suffix(']') else {
+        return Ok((input, None));
+    };
 
-        let kind = ProxyKind::from_str(input_stack.remove(0))?;
+    let start = inner
+        .char_indices()
+        .zip(inner.chars().skip(1))
+        .find(|((_, current), next)| current.is_ascii_digit() && *next == '[')
+        .map(|((index, _), _)| index + 1)
+        .ok_or(ParseError::InvalidRefresh)?;
 
-        let creds = {
-            if !input.contains('@') {
-                None
-            } else {
-                // ad-hoc socks:// <- // part removing fix
-                let login = input_stack.remove(0).replace("//", "");
-                let password = input_stack.remove(0);
+    Ok((&inner[..start], Some(&inner[start + 1..])))
+}
 
-                Some((login.to_owned(), password.to_owned()))
-            }
+fn decode_userinfo(input: &str) -> Result<String, ParseError> {
+    percent_decode_str(input)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| ParseError::InvalidCreds)
+}
+
+/// Splits `login[:password]`, password is empty when omitted
+fn parse_userinfo(input: &str) -> Result<(String, Secret), ParseError> {
+    let (login, password) = input.split_once(':').unwrap_or((input, ""));
+    Ok((decode_userinfo(login)?, decode_userinfo(password)?.into()))
+}
+
+impl FromStr for Proxy {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = input.split_once(':').ok_or(ParseError::InvalidChunkCount)?;
+        let kind = ProxyKind::from_str(kind)?;
+        let rest = rest.strip_prefix("//").unwrap_or(rest);
+
+        let (rest, refresh_url) = split_refresh(rest)?;
+        let refresh_url = refresh_url.map(str::to_owned);
+
+        // raw `@` is tolerated in password, as host never contains it
+        let (creds, host_port) = match rest.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(parse_userinfo(userinfo)?), host_port),
+            None => (None, rest),
         };
-        let addr = input_stack.remove(0).to_string();
-        let port: u16 = input_stack
-            .remove(0)
-            .parse()
-            .map_err(|_| ParseError::InvalidPort)?;
+
+        let (addr, port) = split_host_port(host_port)?;
+        let addr = ProxyHost::from_str(addr)?;
+        let port: u16 = port.parse().map_err(|_| ParseError::InvalidPort)?;
 
         Ok(Self {
             kind,
             addr,
             port,
             creds,
+            http: Default::default(),
             refresh_url,
         })
     }
@@ -0,0 +1,384 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    Blame, BreakerConfig, CircuitBreaker, CircuitState, ConnectError, ConnectOptions,
+    NetworkTarget, ProxiedStream, Proxy,
+};
+
+mod health;
+
+use health::Health;
+pub use health::{HealthCheck, ProxyHealth};
+
+/// Default limit of proxies, tried by a single [`ProxyPool::connect_tcp`]
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// Latency sample of a failed connection, so [`PoolStrategy::EwmaLatency`] avoids failing proxies
+const FAILURE_PENALTY: Duration = Duration::from_secs(10);
+
+/// Weight of the new sample in latency average
+const EWMA_ALPHA: f64 = 0.3;
+
+/// How [`ProxyPool`] picks proxy for the next connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PoolStrategy {
+    /// Each proxy in turn
+    #[default]
+    RoundRobin,
+    /// Uniformly random proxy
+    Random,
+    /// Random proxy, with probability proportional to its weight (see [`ProxyPool::weighted`])
+    Weighted,
+    /// Proxy with the fewest open connections, i.e. ones being established and
+    /// returned [`ProxiedStream`]s, which aren't dropped yet
+    LeastInFlight,
+    /// Less loaded of two random proxies, almost as good as [`PoolStrategy::LeastInFlight`]
+    /// without scanning the whole pool
+    PowerOfTwoChoices,
+    /// Proxy with the lowest moving average of connection time, unmeasured ones go first
+    EwmaLatency,
+}
+
+#[derive(Debug)]
+struct Member {
+    proxy: Proxy,
+    weight: u32,
+    in_flight: Arc<AtomicUsize>,
+    /// Moving average of connection time in nanoseconds, zero until first connection
+    latency: AtomicU64,
+    health: Mutex<Health>,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl Member {
+    /// Whether proxy may be picked, i.e. it isn't ejected by health checks
+    fn in_rotation(&self) -> bool {
+        self.health().in_rotation()
+    }
+
+    /// Whether circuit breaker lets connection through
+    fn is_available(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_none_or(CircuitBreaker::is_available)
+    }
+
+    fn health(&self) -> std::sync::MutexGuard<'_, Health> {
+        self.health
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record(&self, sample: Duration) {
+        let sample = sample.as_nanos().min(u64::MAX as u128) as u64;
+        let _ = self
+            .latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(match average {
+                    0 => sample.max(1),
+                    average => {
+                        (average as f64 * (1.0 - EWMA_ALPHA) + sample as f64 * EWMA_ALPHA) as u64
+                    }
+                })
+            });
+    }
+}
+
+/// Connection through the proxy, counted in [`Member::in_flight`] until dropped,
+/// which is moved into returned [`ProxiedStream`] once established
+#[derive(Debug)]
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/**
+Set of interchangeable proxies, each connection goes through one of them
+
+Proxy is picked according to [`PoolStrategy`]. When connection fails by the fault of the proxy
+(see [`ConnectError::blame`]), it is repeated through another one, up to
+[`ProxyPool::with_max_attempts`] proxies, then the last error is returned.
+
+Dead proxies may be taken out of rotation in advance by health checks
+(see [`ProxyPool::run_health_checks`]), or skipped by circuit breakers
+(see [`ProxyPool::with_circuit_breaker`]).
+
+```rust,no_run
+# async fn run() -> Result<(), proxied::ConnectError> {
+use std::str::FromStr;
+use proxied::{NetworkTarget, PoolStrategy, Proxy, ProxyPool};
+
+let pool = ProxyPool::new(
+    vec![
+        Proxy::from_str("socks5://10.0.0.1:1080").unwrap(),
+        Proxy::from_str("socks5://10.0.0.2:1080").unwrap(),
+    ],
+    PoolStrategy::LeastInFlight,
+)
+.unwrap();
+
+// proxy is loaded by the stream, until it is dropped
+let stream = pool
+    .connect_tcp(NetworkTarget::Domain {
+        domain: "example.com".to_string(),
+        port: 80,
+    })
+    .await?;
+# Ok(())
+# }
+```
+*/
+#[derive(Debug)]
+pub struct ProxyPool {
+    members: Vec<Member>,
+    strategy: PoolStrategy,
+    max_attempts: usize,
+    /// Start of the next round-robin scan
+    next: AtomicUsize,
+}
+
+impl ProxyPool {
+    /// Create pool of equally weighted proxies, `None` if there are no proxies
+    pub fn new(proxies: Vec<Proxy>, strategy: PoolStrategy) -> Option<Self> {
+        Self::weighted(
+            proxies.into_iter().map(|proxy| (proxy, 1)).collect(),
+            strategy,
+        )
+    }
+
+    /// Create pool of proxies along with their weights, `None` if there are no proxies
+    ///
+    /// Weights are used by [`PoolStrategy::Weighted`] only, zero weight proxies are never picked by it,
+    /// so it needs at least one proxy with non-zero weight.
+    pub fn weighted(proxies: Vec<(Proxy, u32)>, strategy: PoolStrategy) -> Option<Self> {
+        let weightless = proxies.iter().all(|(_, weight)| *weight == 0);
+        if proxies.is_empty() || (strategy == PoolStrategy::Weighted && weightless) {
+            return None;
+        }
+
+        let members = proxies
+            .into_iter()
+            .map(|(proxy, weight)| Member {
+                proxy,
+                weight,
+                in_flight: Arc::new(AtomicUsize::new(0)),
+                latency: AtomicU64::new(0),
+                health: Mutex::new(Health::default()),
+                breaker: None,
+            })
+            .collect();
+
+        Some(Self {
+            members,
+            strategy,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Limit of proxies, tried by a single connection (3 by default)
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn strategy(&self) -> PoolStrategy {
+        self.strategy
+    }
+
+    pub fn proxies(&self) -> impl Iterator<Item = &Proxy> {
+        self.members.iter().map(|member| &member.proxy)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Always `false`, as empty pool can't be created
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Create TCP tunnel to the target through one of the proxies
+    pub async fn connect_tcp(&self, target: NetworkTarget) -> Result<ProxiedStream, ConnectError> {
+        self.connect_tcp_with(target, &ConnectOptions::default())
+            .await
+    }
+
+    /// Same as [`ProxyPool::connect_tcp`], but with custom [`ConnectOptions`]
+    ///
+    /// `deadline` limits each attempt rather than the whole connection.
+    pub async fn connect_tcp_with(
+        &self,
+        target: NetworkTarget,
+        options: &ConnectOptions,
+    ) -> Result<ProxiedStream, ConnectError> {
+        let mut tried = Vec::new();
+        let mut last_error = None;
+
+        while tried.len() < self.max_attempts {
+            let Some(index) = self.pick(&tried) else {
+                break;
+            };
+            tried.push(index);
+
+            let member = &self.members[index];
+            let permit = match member
+                .breaker
+                .as_ref()
+                .map(CircuitBreaker::acquire)
+                .transpose()
+            {
+                Ok(permit) => permit,
+                Err(error) => {
+                    last_error = Some(error);
+                    continue;
+                }
+            };
+
+            let started = Instant::now();
+            let in_flight = InFlight::start(&member.in_flight);
+            let result = member.proxy.connect_tcp_with(target.clone(), options).await;
+            if let Some(permit) = permit {
+                permit.record(&result);
+            }
+
+            match result {
+                Ok(stream) => {
+                    member.record(started.elapsed());
+                    return Ok(stream.with_in_flight(in_flight));
+                }
+                Err(error) if error.blame() != Blame::Proxy => return Err(error),
+                Err(error) => {
+                    member.record(started.elapsed().max(FAILURE_PENALTY));
+                    last_error = Some(error);
+                }
+            }
+        }
+
+        // every proxy left has its circuit open
+        Err(last_error.unwrap_or(ConnectError::CircuitOpen))
+    }
+
+    /// Enables [`CircuitBreaker`] of each proxy, so ones with open circuit are skipped
+    pub fn with_circuit_breaker(mut self, config: BreakerConfig) -> Self {
+        for member in &mut self.members {
+            member.breaker = Some(CircuitBreaker::new(config));
+        }
+        self
+    }
+
+    /// Circuit state of each proxy in order of [`ProxyPool::proxies`],
+    /// `None` without [`ProxyPool::with_circuit_breaker`]
+    pub fn circuits(&self) -> Vec<Option<CircuitState>> {
+        self.members
+            .iter()
+            .map(|member| member.breaker.as_ref().map(CircuitBreaker::state))
+            .collect()
+    }
+
+    /// Counts failure of `proxy`, noticed after connection, in its circuit breaker
+    pub fn report_failure(&self, proxy: &Proxy) {
+        let breakers = self
+            .members
+            .iter()
+            .filter(|member| &member.proxy == proxy)
+            .filter_map(|member| member.breaker.as_ref());
+        for breaker in breakers {
+            breaker.record_failure();
+        }
+    }
+
+    /// Index of the next proxy, excluding `tried` ones and ones with open circuit,
+    /// while ejected ones are picked only if there are no others
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        self.pick_among(tried, |member| {
+            member.in_rotation() && member.is_available()
+        })
+        .or_else(|| self.pick_among(tried, Member::is_available))
+    }
+
+    fn pick_among(&self, tried: &[usize], eligible: impl Fn(&Member) -> bool) -> Option<usize> {
+        let len = self.members.len();
+        let usable = |index: &usize| !tried.contains(index) && eligible(&self.members[*index]);
+        // rotating start, so ties of scanning strategies are spread across proxies
+        let scan = || {
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            (0..len).map(move |offset| (start + offset) % len)
+        };
+
+        match self.strategy {
+            PoolStrategy::RoundRobin => scan().find(usable),
+            PoolStrategy::Random => random_among((0..len).filter(usable)),
+            PoolStrategy::Weighted => {
+                let available = || {
+                    (0..len)
+                        .filter(usable)
+                        .map(|index| (index, u64::from(self.members[index].weight)))
+                };
+                let total: u64 = available().map(|(_, weight)| weight).sum();
+                if total == 0 {
+                    return None;
+                }
+
+                let mut point = random(total);
+                available().find_map(|(index, weight)| match point < weight {
+                    true => Some(index),
+                    false => {
+                        point -= weight;
+                        None
+                    }
+                })
+            }
+            PoolStrategy::LeastInFlight => scan()
+                .filter(usable)
+                .min_by_key(|&index| self.members[index].in_flight.load(Ordering::Relaxed)),
+            PoolStrategy::PowerOfTwoChoices => {
+                let first = random_among((0..len).filter(usable))?;
+                let second = random_among((0..len).filter(usable).filter(|&index| index != first));
+                let load = |index: usize| self.members[index].in_flight.load(Ordering::Relaxed);
+                match second {
+                    Some(second) if load(second) < load(first) => Some(second),
+                    _ => Some(first),
+                }
+            }
+            PoolStrategy::EwmaLatency => scan()
+                .filter(usable)
+                .min_by_key(|&index| self.members[index].latency.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Uniformly random item of `indices`
+fn random_among(indices: impl Iterator<Item = usize> + Clone) -> Option<usize> {
+    let count = indices.clone().count() as u64;
+    match count {
+        0 => None,
+        count => indices.clone().nth(random(count) as usize),
+    }
+}
+
+/// Random number below `bound`
+fn random(bound: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("OS random source is available");
+    u64::from_le_bytes(bytes) % bound
+}
@@ -0,0 +1,223 @@
+use std::{future::Future, pin::Pin, task::Poll, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+use super::ProxyPool;
+use crate::{ConnectOptions, NetworkTarget, Proxy};
+
+/**
+Settings of [`ProxyPool`] health checks
+
+Each probe establishes full tunnel to `target` through the proxy, then optionally
+sends `payload` and waits for the response starting with `expect`. Proxy, which fails
+`failure_threshold` probes in a row, is ejected from rotation for `base_ejection`,
+doubled on every next ejection up to `max_ejection`. Once ejection passes,
+proxy is probed again, and re-admitted only if the probe succeeds.
+
+```rust,no_run
+# async fn run(pool: std::sync::Arc<proxied::ProxyPool>) {
+use std::time::Duration;
+use proxied::{HealthCheck, NetworkTarget};
+
+let check = HealthCheck {
+    payload: b"HEAD / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec(),
+    expect: b"HTTP/1.1 ".to_vec(),
+    interval: Duration::from_secs(10),
+    ..HealthCheck::new(NetworkTarget::Domain {
+        domain: "example.com".to_string(),
+        port: 80,
+    })
+};
+
+tokio::spawn(async move { pool.run_health_checks(&check).await });
+# }
+```
+*/
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub target: NetworkTarget,
+    /// Sent through the tunnel after it's established, if not empty
+    pub payload: Vec<u8>,
+    /// Prefix of the response to `payload`, if not empty
+    pub expect: Vec<u8>,
+    /// Options of probe connections
+    pub options: ConnectOptions,
+    /// Limit for the whole probe, along with `payload` exchange
+    pub timeout: Duration,
+    /// Delay between rounds of [`ProxyPool::run_health_checks`]
+    pub interval: Duration,
+    /// Probes, failed in a row, to eject the proxy
+    pub failure_threshold: u32,
+    pub base_ejection: Duration,
+    pub max_ejection: Duration,
+    /// Limit of probes running at once
+    pub concurrency: usize,
+}
+
+impl HealthCheck {
+    pub fn new(target: NetworkTarget) -> Self {
+        Self {
+            target,
+            payload: Vec::new(),
+            expect: Vec::new(),
+            options: ConnectOptions::default(),
+            timeout: Duration::from_secs(10),
+            interval: Duration::from_secs(30),
+            failure_threshold: 3,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(30 * 60),
+            concurrency: 64,
+        }
+    }
+}
+
+/// Health of pooled proxy, see [`ProxyPool::health`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ProxyHealth {
+    /// Probes failed in a row
+    pub failures: u32,
+    /// Whether proxy is out of rotation
+    pub ejected: bool,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Health {
+    failures: u32,
+    /// Ejections in a row, without successful probe in between
+    ejections: u32,
+    /// End of the current ejection, stays set after it passes until successful probe
+    ejected_until: Option<Instant>,
+}
+
+impl Health {
+    pub(super) fn in_rotation(&self) -> bool {
+        self.ejected_until.is_none()
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.ejected_until.is_none_or(|until| until <= now)
+    }
+
+    fn record(&mut self, healthy: bool, check: &HealthCheck, now: Instant) {
+        if healthy {
+            *self = Self::default();
+            return;
+        }
+
+        self.failures += 1;
+        // ejected proxy has single probe to prove it's alive
+        if self.ejected_until.is_some() || self.failures >= check.failure_threshold {
+            let backoff = check
+                .base_ejection
+                .saturating_mul(2u32.saturating_pow(self.ejections))
+                .min(check.max_ejection);
+            self.ejections += 1;
+            self.ejected_until = Some(now + backoff);
+        }
+    }
+}
+
+impl ProxyPool {
+    /// Health of each proxy, in order of [`ProxyPool::proxies`]
+    pub fn health(&self) -> Vec<ProxyHealth> {
+        self.members
+            .iter()
+            .map(|member| {
+                let health = member.health();
+                ProxyHealth {
+                    failures: health.failures,
+                    ejected: health.ejected_until.is_some(),
+                }
+            })
+            .collect()
+    }
+
+    /// Single round of health checks
+    ///
+    /// Probes every proxy in rotation and every ejected one, whose ejection has passed.
+    pub async fn check_health(&self, check: &HealthCheck) {
+        let now = Instant::now();
+        let due = self
+            .members
+            .iter()
+            .filter(|member| member.health().is_due(now));
+
+        let probes = due.map(|member| {
+            Box::pin(async move {
+                let healthy = probe(&member.proxy, check).await;
+                member.health().record(healthy, check, Instant::now());
+            }) as Pin<Box<dyn Future<Output = ()> + Send + '_>>
+        });
+        run_limited(probes, check.concurrency.max(1)).await;
+    }
+
+    /// Health checks every `interval`, never returns
+    ///
+    /// Should be spawned along with [`Arc`](std::sync::Arc) of the pool, and aborted when
+    /// it's no longer needed.
+    pub async fn run_health_checks(&self, check: &HealthCheck) {
+        loop {
+            self.check_health(check).await;
+            tokio::time::sleep(check.interval).await;
+        }
+    }
+}
+
+async fn probe(proxy: &Proxy, check: &HealthCheck) -> bool {
+    let probe = async {
+        let mut stream = proxy
+            .connect_tcp_with(check.target.clone(), &check.options)
+            .await
+            .ok()?;
+
+        if !check.payload.is_empty() {
+            stream.write_all(&check.payload).await.ok()?;
+            stream.flush().await.ok()?;
+        }
+        if !check.expect.is_empty() {
+            let mut response = vec![0u8; check.expect.len()];
+            stream.read_exact(&mut response).await.ok()?;
+            return Some(response == check.expect);
+        }
+        Some(true)
+    };
+
+    matches!(
+        tokio::time::timeout(check.timeout, probe).await,
+        Ok(Some(true))
+    )
+}
+
+/// Runs `futures` to completion, at most `limit` at once
+async fn run_limited<'a>(
+    futures: impl Iterator<Item = Pin<Box<dyn Future<Output = ()> + Send + 'a>>>,
+    limit: usize,
+) {
+    let mut pending = futures;
+    let mut running = Vec::new();
+
+    loop {
+        while running.len() < limit {
+            match pending.next() {
+                Some(future) => running.push(future),
+                None => break,
+            }
+        }
+        if running.is_empty() {
+            return;
+        }
+
+        std::future::poll_fn(|cx| {
+            let before = running.len();
+            running.retain_mut(|future| future.as_mut().poll(cx).is_pending());
+            match running.len() < before {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+    }
+}
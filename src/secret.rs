@@ -0,0 +1,57 @@
+use zeroize::Zeroize;
+
+/**
+Sensitive string, e.g. proxy password
+
+Memory is wiped on drop, while `Debug` never reveals the value. Serialized as a plain string,
+so configs keep their format:
+```rust
+use proxied::Secret;
+
+let password = Secret::from("hunter2");
+assert_eq!(format!("{:?}", password), "***");
+assert_eq!(password.expose(), "hunter2");
+```
+*/
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Actual value, keep it out of logs
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::client::TlsStream;
+
+use crate::{pool::InFlight, NetworkTarget, Proxy};
+
+/// Object safe set of traits, required from the stream to build tunnel over it
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Type-erased stream, e.g. tunnel through the [`ProxyChain`](crate::ProxyChain)
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/**
+Established tunnel to the target
+
+Plain stream for most proxies, or TLS session wrapping it for [`ProxyKind::Https`](crate::ProxyKind::Https).
+Either way it is used just like regular TCP stream, as it implements [`AsyncRead`] and [`AsyncWrite`].
+*/
+#[derive(Debug)]
+pub enum ProxyStream<S = TcpStream> {
+    Plain(S),
+    Tls(Box<TlsStream<S>>),
+}
+
+impl<S> ProxyStream<S> {
+    /// Underlying transport stream, connected to the proxy server
+    pub fn get_ref(&self) -> &S {
+        match self {
+            ProxyStream::Plain(stream) => stream,
+            ProxyStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    /// Whether session with proxy is protected by TLS
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ProxyStream::Tls(_))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ProxyStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ProxyStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ProxyStream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            ProxyStream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            ProxyStream::Plain(stream) => stream.is_write_vectored(),
+            ProxyStream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+/// Response of HTTP proxy to `CONNECT` request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: String,
+    /// Headers in order of appearance, names are kept as sent by the proxy
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    /// Value of the first header with given name (case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Everything, proxy has told about established tunnel
+#[derive(Debug, Clone)]
+pub struct TunnelInfo {
+    /// Proxy, the tunnel goes through (last hop for [`ProxyChain`](crate::ProxyChain))
+    pub proxy: Proxy,
+    /// Resolved address of the proxy, `None` when it was reached through another tunnel
+    pub proxy_addr: Option<SocketAddr>,
+    /// Address, proxy uses to reach the target (SOCKS5 `BND.ADDR` and `BND.PORT`)
+    pub bound_addr: Option<NetworkTarget>,
+    /// Status line and headers of HTTP `CONNECT` response
+    pub http_response: Option<HttpResponse>,
+}
+
+/**
+Established tunnel along with [`TunnelInfo`]
+
+Dereferences to [`ProxyStream`] and implements [`AsyncRead`] and [`AsyncWrite`] itself,
+so it is used just like regular TCP stream.
+
+Proxy may send target data right after its handshake reply (e.g. HTTP proxy pipelining
+SMTP greeting after `200`). Such bytes are buffered and replayed by [`AsyncRead`]
+before anything else is read from the proxy (see [`ProxiedStream::buffered`]).
+That's why [`ProxyStream`] is only lent immutably, and it's unwrapped along with these bytes.
+*/
+#[derive(Debug)]
+pub struct ProxiedStream<S = TcpStream> {
+    stream: ProxyStream<S>,
+    info: TunnelInfo,
+    leftover: Vec<u8>,
+    /// Load of the proxy, when stream was obtained from [`ProxyPool`](crate::ProxyPool)
+    in_flight: Option<InFlight>,
+}
+
+impl<S> ProxiedStream<S> {
+    pub(crate) fn new(stream: ProxyStream<S>, info: TunnelInfo) -> Self {
+        Self {
+            stream,
+            info,
+            leftover: Vec::new(),
+            in_flight: None,
+        }
+    }
+
+    pub(crate) fn with_leftover(mut self, leftover: Vec<u8>) -> Self {
+        self.leftover = leftover;
+        self
+    }
+
+    pub(crate) fn with_in_flight(mut self, in_flight: InFlight) -> Self {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// Tunnel data, received along with the handshake, which wasn't read yet
+    pub fn buffered(&self) -> &[u8] {
+        &self.leftover
+    }
+
+    /// Takes [`ProxiedStream::buffered`] bytes out, so they won't be replayed anymore
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.leftover)
+    }
+
+    pub fn info(&self) -> &TunnelInfo {
+        &self.info
+    }
+
+    pub(crate) fn info_mut(&mut self) -> &mut TunnelInfo {
+        &mut self.info
+    }
+
+    /// Unwraps the stream along with [`ProxiedStream::buffered`] bytes, which are to be read
+    /// before anything else
+    ///
+    /// Unwrapped stream no longer counts towards load of [`ProxyPool`](crate::ProxyPool) proxy.
+    pub fn into_inner(self) -> (ProxyStream<S>, Vec<u8>) {
+        (self.stream, self.leftover)
+    }
+
+    /// Same as [`ProxiedStream::into_inner`], but keeps [`TunnelInfo`] too
+    pub fn into_parts(self) -> (ProxyStream<S>, TunnelInfo, Vec<u8>) {
+        (self.stream, self.info, self.leftover)
+    }
+}
+
+impl<S> Deref for ProxiedStream<S> {
+    type Target = ProxyStream<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ProxiedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.leftover.is_empty() {
+            let len = this.leftover.len().min(buf.remaining());
+            buf.put_slice(&this.leftover[..len]);
+            this.leftover.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ProxiedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
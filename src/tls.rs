@@ -0,0 +1,180 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{ConnectError, ProxyHost};
+
+static WEBPKI_CONFIG: LazyLock<Arc<ClientConfig>> =
+    LazyLock::new(|| Arc::new(config_with_roots(webpki_root_store())));
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn webpki_root_store() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+fn config_with_roots(roots: RootCertStore) -> ClientConfig {
+    ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("ring provider supports default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/**
+TLS settings for the connection to the proxy server itself
+
+Only used by [`ProxyKind::Https`](crate::ProxyKind::Https) proxies, the tunnel to the target
+is unaffected. By default proxy certificate is verified against Mozilla root certificates.
+*/
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    /// Verify proxy certificate against Mozilla root certificates (default)
+    pub fn webpki() -> Self {
+        Self {
+            config: WEBPKI_CONFIG.clone(),
+        }
+    }
+
+    /// Verify proxy certificate only against given root store
+    pub fn with_roots(roots: RootCertStore) -> Self {
+        Self {
+            config: Arc::new(config_with_roots(roots)),
+        }
+    }
+
+    /// Trust custom CA certificates (DER encoded) in addition to Mozilla root certificates
+    pub fn with_custom_ca<'a>(
+        certs: impl IntoIterator<Item = CertificateDer<'a>>,
+    ) -> Result<Self, rustls::Error> {
+        let mut roots = webpki_root_store();
+        for cert in certs {
+            roots.add(cert)?;
+        }
+
+        Ok(Self::with_roots(roots))
+    }
+
+    /// Skip proxy certificate verification entirely
+    ///
+    /// > **Warning**: traffic to the proxy becomes open to man-in-the-middle attacks
+    pub fn insecure() -> Self {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .expect("ring provider supports default protocol versions")
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider())))
+            .with_no_client_auth();
+
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::webpki()
+    }
+}
+
+impl From<Arc<ClientConfig>> for TlsConfig {
+    fn from(config: Arc<ClientConfig>) -> Self {
+        Self { config }
+    }
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+/// Establish TLS session over `stream`, using `host` for SNI and certificate verification
+pub(crate) async fn connect<S>(
+    stream: S,
+    host: &ProxyHost,
+    config: &TlsConfig,
+) -> Result<TlsStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = match host {
+        ProxyHost::Domain(domain) => ServerName::try_from(domain.clone()).map_err(|error| {
+            ConnectError::Tls(std::io::Error::new(std::io::ErrorKind::InvalidInput, error))
+        })?,
+        ProxyHost::Ipv4(ip) => ServerName::IpAddress(IpAddr::V4(*ip).into()),
+        ProxyHost::Ipv6(ip) => ServerName::IpAddress(IpAddr::V6(*ip).into()),
+    };
+
+    TlsConnector::from(config.config.clone())
+        .connect(server_name, stream)
+        .await
+        .map_err(ConnectError::Tls)
+}
+
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::pin,
+    task::Poll,
+};
+
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::{
+    connect::{self, socks5_proto, Deadline},
+    ConnectError, ConnectOptions, NetworkTarget, Proxy, ProxyKind, Stage,
+};
+
+/// `RSV`, `FRAG` and `ATYP` fields of UDP request header
+const UDP_HEADER_PREFIX: usize = 4;
+
+/// Longest UDP request header: prefix, domain length, 255 byte domain and port
+const MAX_UDP_HEADER: usize = UDP_HEADER_PREFIX + 1 + u8::MAX as usize + 2;
+
+/**
+UDP socket, relaying datagrams through SOCKS5 proxy (`UDP ASSOCIATE` command)
+
+Association lives as long as the control TCP connection to the proxy, which is held
+by the socket and closed on drop. Once proxy closes it, receiving fails.
+
+```rust,no_run
+# async fn run() -> Result<(), Box<dyn std::error::Error>> {
+use std::str::FromStr;
+use proxied::{NetworkTarget, Proxy};
+
+let proxy = Proxy::from_str("socks5://127.0.0.1:1080")?;
+let socket = proxy.associate_udp().await?;
+
+let dns = NetworkTarget::IPAddr { socket: "1.1.1.1:53".parse()? };
+socket.send_to(b"...", &dns).await?;
+
+let mut buf = [0u8; 512];
+let (len, from) = socket.recv_from(&mut buf).await?;
+# Ok(())
+# }
+```
+*/
+#[derive(Debug)]
+pub struct ProxiedUdpSocket {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    control: TcpStream,
+}
+
+impl ProxiedUdpSocket {
+    pub(crate) async fn associate(
+        proxy: &Proxy,
+        options: &ConnectOptions,
+    ) -> Result<Self, ConnectError> {
+        if !matches!(proxy.kind, ProxyKind::Socks5 | ProxyKind::Socks5h) {
+            return Err(ConnectError::CommandUnsupported);
+        }
+
+        let deadline = Deadline::after(options.deadline);
+        let mut control = connect::connect_proxy(proxy, options, deadline).await?;
+
+        // datagrams are sent from the same interface, which reaches the proxy
+        let local_ip = control
+            .local_addr()
+            .map_err(ConnectError::io(Stage::ProxyConnect))?
+            .ip();
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0))
+            .await
+            .map_err(ConnectError::io(Stage::ProxyConnect))?;
+        let local_addr = socket
+            .local_addr()
+            .map_err(ConnectError::io(Stage::ProxyConnect))?;
+
+        let deadline = deadline.min(Deadline::after(options.handshake_timeout));
+        socks5_proto::greet(proxy, &mut control, deadline).await?;
+
+        let relay = deadline
+            .run(
+                Stage::Request,
+                socks5_proto::request(
+                    &mut control,
+                    socks5_proto::SOCKS5_CMD_UDP_ASSOCIATE,
+                    &NetworkTarget::IPAddr { socket: local_addr },
+                ),
+            )
+            .await?;
+
+        let relay = match relay {
+            NetworkTarget::IPAddr { socket } => socket,
+            NetworkTarget::Domain { domain, port } => {
+                deadline
+                    .run(
+                        Stage::Request,
+                        options.resolver.lookup(&domain, port, Stage::Request),
+                    )
+                    .await?
+            }
+        };
+
+        // unspecified address means "same host as the proxy"
+        let relay = match relay.ip().is_unspecified() {
+            true => SocketAddr::new(proxy_ip(&control)?, relay.port()),
+            false => relay,
+        };
+
+        Ok(Self {
+            socket,
+            relay,
+            control,
+        })
+    }
+
+    /// Address of the proxy's UDP relay, datagrams are exchanged with
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Local address of the UDP socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sends `buf` to the `target` through the relay, returning number of payload bytes sent
+    pub async fn send_to(&self, buf: &[u8], target: &NetworkTarget) -> io::Result<usize> {
+        let mut packet = Vec::with_capacity(MAX_UDP_HEADER + buf.len());
+        packet.extend_from_slice(&[0x00, 0x00, 0x00]);
+        socks5_proto::encode_target(target, &mut packet)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+        let header_len = packet.len();
+        packet.extend_from_slice(buf);
+
+        let sent = self.socket.send_to(&packet, self.relay).await?;
+        Ok(sent.saturating_sub(header_len))
+    }
+
+    /// Receives single datagram into `buf`, returning its length and the sender
+    ///
+    /// Datagrams not originating from the relay and fragmented ones (unsupported) are dropped.
+    /// Excess payload, not fitting into `buf`, is discarded.
+    ///
+    /// Fails with [`io::ErrorKind::ConnectionAborted`], if proxy has closed the association.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, NetworkTarget)> {
+        let mut packet = vec![0u8; MAX_UDP_HEADER + buf.len()];
+
+        loop {
+            let (len, from) = self.recv_datagram(&mut packet).await?;
+            if from != self.relay || len < UDP_HEADER_PREFIX || packet[2] != 0 {
+                continue;
+            }
+
+            let mut rest = &packet[UDP_HEADER_PREFIX..len];
+            let source = match socks5_proto::read_target(&mut rest, packet[3], Stage::Request).await
+            {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+
+            let payload_len = rest.len().min(buf.len());
+            buf[..payload_len].copy_from_slice(&rest[..payload_len]);
+
+            return Ok((payload_len, source));
+        }
+    }
+
+    /// Receives datagram from the socket, unless control connection is closed first
+    async fn recv_datagram(&self, packet: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut peeked = [0u8; 1];
+        let mut closed = pin!(self.control.peek(&mut peeked));
+        let mut received = pin!(self.socket.recv_from(packet));
+        // proxy isn't supposed to send anything, so stray data stops the watch
+        let mut watching = true;
+
+        std::future::poll_fn(|cx| {
+            if watching {
+                match closed.as_mut().poll(cx) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "proxy has closed UDP association",
+                        )))
+                    }
+                    Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                    Poll::Ready(Ok(_)) => watching = false,
+                    Poll::Pending => {}
+                }
+            }
+            received.as_mut().poll(cx)
+        })
+        .await
+    }
+}
+
+fn proxy_ip(control: &TcpStream) -> Result<IpAddr, ConnectError> {
+    control
+        .peer_addr()
+        .map(|addr| addr.ip())
+        .map_err(ConnectError::io(Stage::Request))
+}
@@ -0,0 +1,143 @@
+//! Proxy stubs and fixtures, shared by integration tests
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+
+use proxied::{NetworkTarget, Proxy, ProxyHost, ProxyKind};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// SOCKS5 proxy on 127.0.0.1, which accepts every `CONNECT` request
+pub struct Socks5Stub {
+    pub addr: SocketAddr,
+    /// Target of every accepted request, in order
+    pub targets: mpsc::UnboundedReceiver<NetworkTarget>,
+    /// Accept loop, aborting it makes proxy unreachable
+    pub server: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Socks5Stub {
+    pub async fn spawn() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (targets_tx, targets) = mpsc::unbounded_channel();
+
+        let server = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let targets_tx = targets_tx.clone();
+                tokio::spawn(async move {
+                    socks5_greeting(&mut stream).await?;
+                    let target = socks5_request(&mut stream, 0).await?;
+                    let _ = targets_tx.send(target);
+                    anyhow::Ok(())
+                });
+            }
+            anyhow::Ok(())
+        });
+
+        Ok(Self {
+            addr,
+            targets,
+            server,
+        })
+    }
+
+    pub fn proxy(&self) -> Proxy {
+        proxy(ProxyKind::Socks5, self.addr)
+    }
+}
+
+/// Reads SOCKS5 greeting with a single method and selects "no authentication"
+pub async fn socks5_greeting(stream: &mut TcpStream) -> anyhow::Result<()> {
+    let mut greeting = [0u8; 3];
+    stream.read_exact(&mut greeting).await?;
+    stream.write_all(&[5, 0]).await?;
+    Ok(())
+}
+
+/// Reads SOCKS5 request with any address type, answering it with `reply` code
+pub async fn socks5_request(stream: &mut TcpStream, reply: u8) -> anyhow::Result<NetworkTarget> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let target = match header[3] {
+        3 => {
+            let len = stream.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain).await?;
+            NetworkTarget::Domain {
+                domain: String::from_utf8(domain)?,
+                port: stream.read_u16().await?,
+            }
+        }
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            NetworkTarget::IPAddr {
+                socket: (ip, stream.read_u16().await?).into(),
+            }
+        }
+        _ => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            NetworkTarget::IPAddr {
+                socket: (ip, stream.read_u16().await?).into(),
+            }
+        }
+    };
+
+    stream
+        .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(target)
+}
+
+/// Reads HTTP request head, up to the empty line
+pub async fn read_head(stream: &mut BufReader<TcpStream>) -> anyhow::Result<String> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            anyhow::bail!("connection closed");
+        }
+        head.push_str(&line);
+        if line == "\r\n" {
+            return Ok(head);
+        }
+    }
+}
+
+/// Proxy without credentials, listening on `addr`
+pub fn proxy(kind: ProxyKind, addr: SocketAddr) -> Proxy {
+    proxy_at(kind, addr.ip().into(), addr.port())
+}
+
+/// Proxy without credentials, e.g. with domain, which is resolved by the test
+pub fn proxy_at(kind: ProxyKind, addr: ProxyHost, port: u16) -> Proxy {
+    Proxy {
+        kind,
+        addr,
+        port,
+        creds: None,
+        http: Default::default(),
+        refresh_url: None,
+    }
+}
+
+/// Target from TEST-NET-1, which is never reached, as stub proxies don't connect anywhere
+pub fn target() -> NetworkTarget {
+    NetworkTarget::IPAddr {
+        socket: "192.0.2.1:443".parse().unwrap(),
+    }
+}
+
+/// Domain target, passed to the proxy as is
+pub fn domain_target() -> NetworkTarget {
+    NetworkTarget::Domain {
+        domain: "example.com".to_string(),
+        port: 443,
+    }
+}
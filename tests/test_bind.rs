@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+
+use proxied::{ConnectError, NetworkTarget, ProxyKind, Socks5Reply};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+mod common;
+
+use common::{proxy, socks5_greeting, target};
+
+/// SOCKS5 proxy, which answers `BIND` with `first` reply, then with `second` and `payload`
+async fn spawn_bind_proxy(
+    first: Vec<u8>,
+    second: Vec<u8>,
+    payload: &'static [u8],
+) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        socks5_greeting(&mut stream).await?;
+
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await?;
+        anyhow::ensure!(request[1] == 2, "expected BIND");
+
+        stream.write_all(&first).await?;
+        stream.write_all(&second).await?;
+        stream.write_all(payload).await?;
+
+        let _ = stream.read(&mut [0u8; 1]).await;
+        anyhow::Ok(())
+    });
+
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn test_bind_accept() -> anyhow::Result<()> {
+    let addr = spawn_bind_proxy(
+        // unspecified address, port 5000
+        vec![5, 0, 0, 1, 0, 0, 0, 0, 0x13, 0x88],
+        // peer 10.1.2.3:20
+        vec![5, 0, 0, 1, 10, 1, 2, 3, 0, 20],
+        b"hello",
+    )
+    .await?;
+
+    let listener = proxy(ProxyKind::Socks5, addr).bind_tcp(target()).await?;
+    assert_eq!(
+        listener.bound_addr(),
+        &NetworkTarget::IPAddr {
+            socket: "127.0.0.1:5000".parse()?
+        }
+    );
+
+    let (mut stream, peer) = listener.accept().await?;
+    assert_eq!(
+        peer,
+        NetworkTarget::IPAddr {
+            socket: "10.1.2.3:20".parse()?
+        }
+    );
+
+    let mut payload = [0u8; 5];
+    stream.read_exact(&mut payload).await?;
+    assert_eq!(&payload, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bind_second_reply_failed() -> anyhow::Result<()> {
+    let addr = spawn_bind_proxy(
+        vec![5, 0, 0, 1, 192, 0, 2, 1, 0x13, 0x88],
+        vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0],
+        b"",
+    )
+    .await?;
+
+    let listener = proxy(ProxyKind::Socks5, addr).bind_tcp(target()).await?;
+    assert_eq!(listener.bound_addr().to_string(), "192.0.2.1:5000");
+
+    let error = listener.accept().await.err().unwrap();
+    assert!(matches!(
+        error,
+        ConnectError::Socks5 {
+            reply: Socks5Reply::ConnectionRefused
+        }
+    ));
+
+    Ok(())
+}
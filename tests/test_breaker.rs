@@ -0,0 +1,243 @@
+use std::{
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use proxied::{
+    BreakerConfig, CircuitBreaker, CircuitState, ConnectError, NetworkTarget, PoolStrategy, Proxy,
+    ProxyKind, ProxyPool,
+};
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::{proxy, socks5_greeting, socks5_request, target};
+
+const ACCEPT: u8 = 0;
+/// Closes connection right away
+const DROP: u8 = 1;
+/// Never answers the greeting
+const STALL: u8 = 2;
+
+/// SOCKS5 proxy with switchable behaviour, along with count of accepted connections
+struct TestProxy {
+    proxy: Proxy,
+    mode: Arc<AtomicU8>,
+    accepted: Arc<AtomicUsize>,
+}
+
+impl TestProxy {
+    async fn spawn(mode: u8) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mode = Arc::new(AtomicU8::new(mode));
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let (current, counter) = (mode.clone(), accepted.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mode = current.load(Ordering::SeqCst);
+                if mode == DROP {
+                    continue;
+                }
+
+                tokio::spawn(async move {
+                    if mode == STALL {
+                        std::future::pending::<()>().await;
+                    }
+                    socks5_greeting(&mut stream).await?;
+                    socks5_request(&mut stream, 0).await?;
+                    anyhow::Ok(())
+                });
+            }
+        });
+
+        Ok(Self {
+            proxy: proxy(ProxyKind::Socks5, addr),
+            mode,
+            accepted,
+        })
+    }
+
+    fn set_mode(&self, mode: u8) {
+        self.mode.store(mode, Ordering::SeqCst);
+    }
+
+    fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::SeqCst)
+    }
+}
+
+#[tokio::test]
+async fn test_breaker_states() -> anyhow::Result<()> {
+    let proxy = TestProxy::spawn(DROP).await?;
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 2,
+        open_for: Duration::from_millis(200),
+        ..Default::default()
+    });
+
+    for _ in 0..2 {
+        let error = breaker
+            .run(proxy.proxy.connect_tcp(target()))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ConnectError::IO { .. }));
+    }
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    // open circuit doesn't reach the proxy
+    let error = breaker
+        .run(proxy.proxy.connect_tcp(target()))
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ConnectError::CircuitOpen));
+    assert_eq!(proxy.accepted(), 2);
+
+    // failed trial opens it again
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    assert!(breaker
+        .run(proxy.proxy.connect_tcp(target()))
+        .await
+        .is_err());
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    // successful trial closes it
+    proxy.set_mode(ACCEPT);
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    breaker.run(proxy.proxy.connect_tcp(target())).await?;
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    // failures, reported by user
+    breaker.record_failure();
+    breaker.record_success();
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    breaker.reset();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_breaker_half_open_trials() -> anyhow::Result<()> {
+    let proxy = TestProxy::spawn(STALL).await?;
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 1,
+        open_for: Duration::ZERO,
+        ..Default::default()
+    });
+    breaker.record_failure();
+    assert!(breaker.is_available());
+
+    // the only trial is in flight
+    let mut trial = Box::pin(breaker.run(proxy.proxy.connect_tcp(target())));
+    assert!(futures::poll!(&mut trial).is_pending());
+    assert!(!breaker.is_available());
+    let error = breaker
+        .run(proxy.proxy.connect_tcp(target()))
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ConnectError::CircuitOpen));
+
+    // cancelled trial frees its slot
+    drop(trial);
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    assert!(breaker.is_available());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_breaker_ignores_target_errors() -> anyhow::Result<()> {
+    let proxy = TestProxy::spawn(ACCEPT).await?;
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 2,
+        open_for: Duration::ZERO,
+        ..Default::default()
+    });
+
+    // caller's fault doesn't reset failures in a row
+    let invalid = NetworkTarget::Domain {
+        domain: "a".repeat(300),
+        port: 443,
+    };
+    breaker.record_failure();
+    let error = breaker
+        .run(proxy.proxy.connect_tcp(invalid.clone()))
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ConnectError::ExceededMaxDomainLen));
+    breaker.record_failure();
+    assert_ne!(breaker.state(), CircuitState::Closed);
+
+    // nor does it close half-open circuit, while freeing the trial slot
+    assert!(breaker.run(proxy.proxy.connect_tcp(invalid)).await.is_err());
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    assert!(breaker.is_available());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_breaker_zero_trials() -> anyhow::Result<()> {
+    let proxy = TestProxy::spawn(ACCEPT).await?;
+    let breaker = CircuitBreaker::new(BreakerConfig {
+        failure_threshold: 1,
+        open_for: Duration::ZERO,
+        half_open_trials: 0,
+        ..Default::default()
+    });
+    assert_eq!(breaker.config().half_open_trials, 1);
+
+    breaker.record_failure();
+    assert!(breaker.is_available());
+    breaker.run(proxy.proxy.connect_tcp(target())).await?;
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_breaker_in_pool() -> anyhow::Result<()> {
+    let bad = TestProxy::spawn(DROP).await?;
+    let good = TestProxy::spawn(ACCEPT).await?;
+
+    let pool = ProxyPool::new(
+        vec![bad.proxy.clone(), good.proxy.clone()],
+        PoolStrategy::RoundRobin,
+    )
+    .unwrap()
+    .with_max_attempts(1)
+    .with_circuit_breaker(BreakerConfig {
+        failure_threshold: 1,
+        ..Default::default()
+    });
+
+    assert!(pool.connect_tcp(target()).await.is_err());
+    assert_eq!(
+        pool.circuits(),
+        [Some(CircuitState::Open), Some(CircuitState::Closed)]
+    );
+
+    // open circuit is skipped
+    for _ in 0..4 {
+        pool.connect_tcp(target()).await?;
+    }
+    assert_eq!((bad.accepted(), good.accepted()), (1, 4));
+
+    pool.report_failure(&good.proxy);
+    let error = pool.connect_tcp(target()).await.unwrap_err();
+    assert!(matches!(error, ConnectError::CircuitOpen));
+    assert_eq!(good.accepted(), 4);
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+
+use proxied::{Blame, ConnectError, NetworkTarget, ProxyChain, ProxyKind};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+mod common;
+
+use common::{proxy, socks5_greeting};
+
+async fn spawn_echo() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// No-auth SOCKS5 proxy, supporting only IPv4 `CONNECT`
+async fn spawn_socks5() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                socks5_greeting(&mut stream).await?;
+
+                let mut request = [0u8; 10];
+                stream.read_exact(&mut request).await?;
+                let target = SocketAddr::from((
+                    [request[4], request[5], request[6], request[7]],
+                    u16::from_be_bytes([request[8], request[9]]),
+                ));
+
+                let mut upstream = TcpStream::connect(target).await?;
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// HTTP `CONNECT` proxy without authentication
+async fn spawn_http() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await?;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await?;
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+
+                let authority = request_line.split(' ').nth(1).unwrap_or_default();
+                let mut upstream = TcpStream::connect(authority).await?;
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn test_heterogeneous_chain() -> anyhow::Result<()> {
+    let echo = spawn_echo().await?;
+    let chain = ProxyChain::new(vec![
+        proxy(ProxyKind::Socks5, spawn_socks5().await?),
+        proxy(ProxyKind::Http, spawn_http().await?),
+        proxy(ProxyKind::Socks5, spawn_socks5().await?),
+    ])
+    .unwrap();
+
+    let mut connection = tokio::spawn(async move {
+        chain
+            .connect_tcp(NetworkTarget::IPAddr { socket: echo })
+            .await
+    })
+    .await??;
+
+    connection.write_all(b"through three hops").await?;
+    let mut buf = [0u8; 18];
+    connection.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"through three hops");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chain_unreachable_hop() -> anyhow::Result<()> {
+    let echo = spawn_echo().await?;
+
+    let dead = TcpListener::bind("127.0.0.1:0").await?;
+    let dead_addr = dead.local_addr()?;
+    drop(dead);
+
+    let chain = ProxyChain::new(vec![
+        proxy(ProxyKind::Socks5, spawn_socks5().await?),
+        proxy(ProxyKind::Http, dead_addr),
+    ])
+    .unwrap();
+
+    let error = chain
+        .connect_tcp(NetworkTarget::IPAddr { socket: echo })
+        .await
+        .err()
+        .unwrap();
+
+    assert!(matches!(error, ConnectError::Chain { hop: 0, .. }));
+    assert_eq!(error.blame(), Blame::Proxy);
+    assert!(ProxyChain::new(Vec::new()).is_none());
+
+    Ok(())
+}
@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use proxied::{
+    ConnectError, ConnectOptions, DnsCacheConfig, NetworkTarget, ProxyHost, ProxyKind, Resolve,
+    Resolved, Resolver, Resolving, Stage, SystemResolver,
+};
+
+mod common;
+
+use common::proxy_at;
+
+/// Resolver stub, which counts lookups of each domain
+///
+/// `<ttl in ms>.ttl.test` resolves with given TTL, `empty.test` has no records,
+/// `fail.test` fails and everything else resolves without TTL.
+#[derive(Default, Clone)]
+struct Stub {
+    lookups: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Stub {
+    fn lookups(&self, domain: &str) -> usize {
+        self.lookups
+            .lock()
+            .unwrap()
+            .get(domain)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl Resolve for Stub {
+    fn resolve<'a>(&'a self, domain: &'a str) -> Resolving<'a> {
+        *self
+            .lookups
+            .lock()
+            .unwrap()
+            .entry(domain.to_string())
+            .or_default() += 1;
+
+        Box::pin(async move {
+            let addrs = vec![IpAddr::from([127, 0, 0, 1])];
+            match domain {
+                "empty.test" => Ok(Resolved::default()),
+                "fail.test" => Err(std::io::Error::other("server failure")),
+                _ => match domain.strip_suffix(".ttl.test") {
+                    Some(ttl) => Ok(Resolved {
+                        addrs,
+                        ttl: Some(Duration::from_millis(ttl.parse().unwrap())),
+                    }),
+                    None => Ok(addrs.into()),
+                },
+            }
+        })
+    }
+}
+
+/// Connects through `domain` proxy, which is resolved by `resolver` to a closed port
+async fn lookup(resolver: &Resolver, domain: &str) -> ConnectError {
+    let proxy = proxy_at(ProxyKind::Socks5, ProxyHost::Domain(domain.to_string()), 1);
+    let options = ConnectOptions {
+        resolver: resolver.clone(),
+        ..Default::default()
+    };
+    let target = NetworkTarget::Domain {
+        domain: "example.com".to_string(),
+        port: 443,
+    };
+
+    proxy.connect_tcp_with(target, &options).await.unwrap_err()
+}
+
+fn cached_domains(resolver: &Resolver) -> Vec<String> {
+    resolver
+        .cached()
+        .into_iter()
+        .map(|entry| entry.domain)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_dns_cache_ttl() -> anyhow::Result<()> {
+    let stub = Stub::default();
+    let resolver = Resolver::with_cache(
+        stub.clone(),
+        DnsCacheConfig {
+            min_ttl: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+
+    for _ in 0..2 {
+        lookup(&resolver, "200.ttl.test").await;
+        lookup(&resolver, "proxy.test").await;
+    }
+    assert_eq!(stub.lookups("200.ttl.test"), 1);
+    assert_eq!(stub.lookups("proxy.test"), 1);
+
+    let entries = resolver.cached();
+    assert_eq!(entries[0].addrs, [IpAddr::from([127, 0, 0, 1])]);
+    assert!(entries[0].expires_in <= Duration::from_millis(200));
+    assert!(entries[1].expires_in > Duration::from_secs(30));
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(cached_domains(&resolver), ["proxy.test"]);
+
+    lookup(&resolver, "200.ttl.test").await;
+    lookup(&resolver, "proxy.test").await;
+    assert_eq!(stub.lookups("200.ttl.test"), 2);
+    assert_eq!(stub.lookups("proxy.test"), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_cache_ttl_clamp() -> anyhow::Result<()> {
+    let resolver = Resolver::with_cache(
+        Stub::default(),
+        DnsCacheConfig {
+            min_ttl: Duration::from_secs(10),
+            max_ttl: Duration::from_secs(60),
+            ..Default::default()
+        },
+    );
+
+    lookup(&resolver, "0.ttl.test").await;
+    lookup(&resolver, "86400000.ttl.test").await;
+
+    let entries = resolver.cached();
+    assert!(entries[0].expires_in > Duration::from_secs(5));
+    assert!(entries[0].expires_in <= Duration::from_secs(10));
+    assert!(entries[1].expires_in > Duration::from_secs(30));
+    assert!(entries[1].expires_in <= Duration::from_secs(60));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_cache_zero_ttl() -> anyhow::Result<()> {
+    let stub = Stub::default();
+    let resolver = Resolver::with_cache(
+        stub.clone(),
+        DnsCacheConfig {
+            min_ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+
+    // answers are used once, but never cached
+    for _ in 0..2 {
+        let error = lookup(&resolver, "0.ttl.test").await;
+        assert_eq!(error.stage(), Stage::ProxyConnect);
+
+        let error = lookup(&resolver, "empty.test").await;
+        assert!(matches!(
+            error,
+            ConnectError::DnsNameNotResolved {
+                stage: Stage::ProxyDns
+            }
+        ));
+    }
+    assert_eq!(stub.lookups("0.ttl.test"), 2);
+    assert_eq!(stub.lookups("empty.test"), 2);
+    assert!(resolver.cached().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_cache_lru() -> anyhow::Result<()> {
+    let stub = Stub::default();
+    let resolver = Resolver::with_cache(
+        stub.clone(),
+        DnsCacheConfig {
+            capacity: 2,
+            ..Default::default()
+        },
+    );
+
+    lookup(&resolver, "a.test").await;
+    lookup(&resolver, "b.test").await;
+    lookup(&resolver, "a.test").await;
+    lookup(&resolver, "c.test").await;
+    assert_eq!(cached_domains(&resolver), ["a.test", "c.test"]);
+
+    lookup(&resolver, "b.test").await;
+    assert_eq!(cached_domains(&resolver), ["c.test", "b.test"]);
+    assert_eq!(stub.lookups("a.test"), 1);
+    assert_eq!(stub.lookups("b.test"), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_cache_negative() -> anyhow::Result<()> {
+    let stub = Stub::default();
+    let resolver = Resolver::with_cache(
+        stub.clone(),
+        DnsCacheConfig {
+            negative_ttl: Duration::from_millis(200),
+            ..Default::default()
+        },
+    );
+
+    for _ in 0..2 {
+        let error = lookup(&resolver, "empty.test").await;
+        assert!(matches!(
+            error,
+            ConnectError::DnsNameNotResolved {
+                stage: Stage::ProxyDns
+            }
+        ));
+    }
+    assert_eq!(stub.lookups("empty.test"), 1);
+
+    let entries = resolver.cached();
+    assert!(entries[0].addrs.is_empty());
+    assert!(entries[0].expires_in <= Duration::from_millis(200));
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    lookup(&resolver, "empty.test").await;
+    assert_eq!(stub.lookups("empty.test"), 2);
+
+    // failures aren't cached
+    for _ in 0..2 {
+        let error = lookup(&resolver, "fail.test").await;
+        assert!(matches!(error, ConnectError::IO { .. }));
+    }
+    assert_eq!(stub.lookups("fail.test"), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_cache_flush() -> anyhow::Result<()> {
+    let stub = Stub::default();
+    let resolver = Resolver::new(stub.clone());
+
+    lookup(&resolver, "a.test").await;
+    lookup(&resolver, "b.test").await;
+
+    assert!(resolver.flush_domain("a.test"));
+    assert!(!resolver.flush_domain("a.test"));
+    assert_eq!(cached_domains(&resolver), ["b.test"]);
+
+    lookup(&resolver, "a.test").await;
+    assert_eq!(stub.lookups("a.test"), 2);
+
+    resolver.flush();
+    assert!(resolver.cached().is_empty());
+
+    Ok(())
+}
+
+/// Unknown domain is cached as empty answer, rather than failure of the system resolver
+#[tokio::test]
+async fn test_dns_cache_system_not_found() -> anyhow::Result<()> {
+    let resolver = Resolver::new(SystemResolver);
+
+    let error = lookup(&resolver, "proxied-test.invalid").await;
+    assert!(matches!(
+        error,
+        ConnectError::DnsNameNotResolved {
+            stage: Stage::ProxyDns
+        }
+    ));
+
+    let cached = resolver.cached();
+    assert_eq!(cached.len(), 1);
+    assert_eq!(cached[0].domain, "proxied-test.invalid");
+    assert!(cached[0].addrs.is_empty());
+
+    // answered from negative cache, so the entry isn't renewed
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let error = lookup(&resolver, "proxied-test.invalid").await;
+    assert!(matches!(error, ConnectError::DnsNameNotResolved { .. }));
+    let renewed = resolver.cached();
+    assert_eq!(renewed.len(), 1);
+    assert!(renewed[0].expires_in < cached[0].expires_in);
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use proxied::{Blame, ConnectOptions, DnsMode, NetworkTarget, ProxyKind, Stage};
+
+mod common;
+
+use common::{proxy, Socks5Stub};
+
+fn options(dns: DnsMode) -> ConnectOptions {
+    ConnectOptions {
+        dns,
+        dns_timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    }
+}
+
+fn localhost() -> NetworkTarget {
+    NetworkTarget::Domain {
+        domain: "localhost".to_string(),
+        port: 8080,
+    }
+}
+
+fn unresolvable() -> NetworkTarget {
+    NetworkTarget::Domain {
+        domain: "proxied-test.invalid".to_string(),
+        port: 80,
+    }
+}
+
+#[tokio::test]
+async fn test_dns_local() -> anyhow::Result<()> {
+    let mut stub = Socks5Stub::spawn().await?;
+    let proxy = stub.proxy();
+
+    proxy
+        .connect_tcp_with(localhost(), &options(DnsMode::Local))
+        .await?;
+    let target = stub.targets.recv().await.unwrap();
+    assert!(
+        matches!(target, NetworkTarget::IPAddr { socket } if socket.ip().is_loopback() && socket.port() == 8080),
+        "{target:?}"
+    );
+
+    // remote is the default
+    proxy.connect_tcp(localhost()).await?;
+    assert_eq!(stub.targets.recv().await.unwrap(), localhost());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_socks5h_always_remote() -> anyhow::Result<()> {
+    let mut stub = Socks5Stub::spawn().await?;
+
+    proxy(ProxyKind::Socks5h, stub.addr)
+        .connect_tcp_with(localhost(), &options(DnsMode::Local))
+        .await?;
+    assert_eq!(stub.targets.recv().await.unwrap(), localhost());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dns_local_then_remote() -> anyhow::Result<()> {
+    let mut stub = Socks5Stub::spawn().await?;
+    let proxy = stub.proxy();
+
+    proxy
+        .connect_tcp_with(unresolvable(), &options(DnsMode::LocalThenRemote))
+        .await?;
+    assert_eq!(stub.targets.recv().await.unwrap(), unresolvable());
+
+    let error = proxy
+        .connect_tcp_with(unresolvable(), &options(DnsMode::Local))
+        .await
+        .unwrap_err();
+    assert_eq!(error.stage(), Stage::TargetDns);
+    assert_eq!(error.blame(), Blame::Target);
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::net::SocketAddr;
+
+use proxied::{Blame, ConnectError, Proxy, ProxyKind, Socks5Reply, Stage};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+mod common;
+
+use common::target;
+
+/// Accepts single connection, reads `request_len` bytes and writes `reply` for each exchange,
+/// returning everything client has sent once it closes the connection
+async fn spawn_scripted_proxy(
+    script: Vec<(usize, Vec<u8>)>,
+) -> anyhow::Result<(SocketAddr, JoinHandle<anyhow::Result<Vec<u8>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        let (mut stream, _): (TcpStream, _) = listener.accept().await?;
+        let mut received = Vec::new();
+        for (request_len, reply) in script {
+            let mut request = vec![0u8; request_len];
+            stream.read_exact(&mut request).await?;
+            received.extend_from_slice(&request);
+            stream.write_all(&reply).await?;
+        }
+
+        // keep connection open until client is done
+        let _ = stream.read_to_end(&mut received).await;
+        Ok(received)
+    });
+
+    Ok((addr, handle))
+}
+
+fn proxy(kind: ProxyKind, addr: SocketAddr, creds: Option<(&str, &str)>) -> Proxy {
+    Proxy {
+        creds: creds.map(|(login, password)| (login.to_string(), password.into())),
+        ..common::proxy(kind, addr)
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_reply_code() -> anyhow::Result<()> {
+    let (addr, _) = spawn_scripted_proxy(vec![
+        (3, vec![5, 0]),
+        (10, vec![5, 4, 0, 1, 0, 0, 0, 0, 0, 0]),
+    ])
+    .await?;
+
+    let error = proxy(ProxyKind::Socks5, addr, None)
+        .connect_tcp(target())
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error,
+        ConnectError::Socks5 {
+            reply: Socks5Reply::HostUnreachable
+        }
+    ));
+    assert_eq!(error.stage(), Stage::Request);
+    assert_eq!(error.blame(), Blame::Target);
+    assert!(error.is_retryable());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks4_rejected() -> anyhow::Result<()> {
+    // request for IPv4 target with empty USERID
+    let (addr, _) = spawn_scripted_proxy(vec![(9, vec![0, 0x5B, 0, 0, 0, 0, 0, 0])]).await?;
+
+    let error = proxy(ProxyKind::Socks4, addr, None)
+        .connect_tcp(target())
+        .await
+        .unwrap_err();
+
+    assert!(matches!(error, ConnectError::Socks4Rejected));
+    assert_eq!(error.stage(), Stage::Request);
+    assert_eq!(error.blame(), Blame::Target);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_auth_rejected() -> anyhow::Result<()> {
+    // greeting with two methods, then RFC 1929 request with 4 byte username and password
+    let (addr, _) = spawn_scripted_proxy(vec![(4, vec![5, 2]), (11, vec![1, 1])]).await?;
+
+    let error = proxy(ProxyKind::Socks5, addr, Some(("user", "pass")))
+        .connect_tcp(target())
+        .await
+        .unwrap_err();
+
+    assert!(matches!(error, ConnectError::AuthFailed { .. }));
+    assert_eq!(error.stage(), Stage::Auth);
+    assert_eq!(error.blame(), Blame::Proxy);
+    assert!(!error.is_retryable());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_status() -> anyhow::Result<()> {
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target());
+    let (addr, server) = spawn_scripted_proxy(vec![(
+        request.len(),
+        b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n".to_vec(),
+    )])
+    .await?;
+
+    let error = proxy(ProxyKind::Http, addr, None)
+        .connect_tcp(target())
+        .await
+        .unwrap_err();
+
+    match &error {
+        ConnectError::Http { status, reason } => {
+            assert_eq!(*status, 502);
+            assert_eq!(reason, "Bad Gateway");
+        }
+        other => panic!("unexpected error: {other:?}"),
+    }
+    assert_eq!(error.blame(), Blame::Target);
+    assert_eq!(server.await??, request.as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_connect_refused() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    drop(listener);
+
+    let error = proxy(ProxyKind::Socks5, addr, None)
+        .connect_tcp(target())
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.stage(), Stage::ProxyConnect);
+    assert_eq!(error.blame(), Blame::Proxy);
+
+    Ok(())
+}
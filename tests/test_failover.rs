@@ -0,0 +1,131 @@
+use std::{net::SocketAddr, time::Duration};
+
+use proxied::{
+    Blame, ConnectError, ConnectOptions, DnsCacheConfig, Proxy, ProxyHost, ProxyKind, Resolver,
+    Stage, StaticResolver,
+};
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::{proxy_at, target, Socks5Stub};
+
+/// Proxy domain, resolved to 127.0.0.2 and 127.0.0.3 where nothing listens, then to 127.0.0.1
+fn failover_setup(port: u16, config: DnsCacheConfig) -> anyhow::Result<(Proxy, ConnectOptions)> {
+    let hosts = StaticResolver::new().with_host(
+        "proxy.test",
+        [
+            "127.0.0.2".parse()?,
+            "127.0.0.3".parse()?,
+            "127.0.0.1".parse()?,
+        ],
+    );
+    let options = ConnectOptions {
+        resolver: Resolver::with_cache(hosts, config),
+        ..Default::default()
+    };
+    let proxy = proxy_at(
+        ProxyKind::Socks5,
+        ProxyHost::Domain("proxy.test".to_string()),
+        port,
+    );
+
+    Ok((proxy, options))
+}
+
+fn attempted(error: &ConnectError) -> Vec<SocketAddr> {
+    match error {
+        ConnectError::AllAddrsFailed { attempts } => {
+            attempts.iter().map(|(addr, _)| *addr).collect()
+        }
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[tokio::test]
+async fn test_failover_with_cooldown() -> anyhow::Result<()> {
+    let Socks5Stub { addr, server, .. } = Socks5Stub::spawn().await?;
+    let port = addr.port();
+    let (proxy, options) = failover_setup(port, DnsCacheConfig::default())?;
+    let addr = |ip: &str| SocketAddr::new(ip.parse().unwrap(), port);
+
+    let stream = proxy.connect_tcp_with(target(), &options).await?;
+    assert_eq!(stream.info().proxy_addr, Some(addr("127.0.0.1")));
+
+    server.abort();
+    let _ = server.await;
+
+    // failed addresses are in cooldown, so the working one is tried first
+    let error = proxy
+        .connect_tcp_with(target(), &options)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        attempted(&error),
+        [addr("127.0.0.1"), addr("127.0.0.3"), addr("127.0.0.2")]
+    );
+    assert_eq!(error.stage(), Stage::ProxyConnect);
+    assert_eq!(error.blame(), Blame::Proxy);
+    assert!(error.is_retryable());
+    assert!(error.to_string().contains(&addr("127.0.0.3").to_string()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failover_without_cooldown() -> anyhow::Result<()> {
+    let Socks5Stub { addr, server, .. } = Socks5Stub::spawn().await?;
+    let port = addr.port();
+    let config = DnsCacheConfig {
+        dead_cooldown: Duration::ZERO,
+        ..Default::default()
+    };
+    let (proxy, options) = failover_setup(port, config)?;
+    let addr = |ip: &str| SocketAddr::new(ip.parse().unwrap(), port);
+
+    proxy.connect_tcp_with(target(), &options).await?;
+
+    server.abort();
+    let _ = server.await;
+
+    // plain round-robin order, starting after the first address of previous connection
+    let error = proxy
+        .connect_tcp_with(target(), &options)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        attempted(&error),
+        [addr("127.0.0.3"), addr("127.0.0.1"), addr("127.0.0.2")]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_single_addr_error() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    drop(listener);
+
+    let options = ConnectOptions {
+        resolver: Resolver::new(
+            StaticResolver::new().with_host("proxy.test", ["127.0.0.1".parse()?]),
+        ),
+        ..Default::default()
+    };
+    let (proxy, _) = failover_setup(port, DnsCacheConfig::default())?;
+
+    let error = proxy
+        .connect_tcp_with(target(), &options)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        ConnectError::IO {
+            stage: Stage::ProxyConnect,
+            ..
+        }
+    ));
+
+    Ok(())
+}
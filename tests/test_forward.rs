@@ -0,0 +1,280 @@
+use std::{net::SocketAddr, time::Duration};
+
+use proxied::{Blame, ConnectError, ForwardRequest, Proxy, ProxyKind};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+mod common;
+
+use common::proxy;
+
+/// Request, received by the proxy on connection with given index
+#[derive(Debug)]
+struct Received {
+    connection: usize,
+    head: String,
+    body: Vec<u8>,
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> anyhow::Result<(String, Vec<u8>)> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            anyhow::bail!("connection closed");
+        }
+        head.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+
+    let length = header(&head, "Content-Length").map_or(Ok(0), str::parse)?;
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await?;
+    Ok((head, body))
+}
+
+/// Forward proxy, answering each request with `respond`
+///
+/// Connection is closed after the response, when `respond` says so.
+async fn spawn_forward_proxy(
+    respond: fn(&str) -> (&'static [u8], bool),
+) -> anyhow::Result<(SocketAddr, mpsc::UnboundedReceiver<Received>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        for connection in 0.. {
+            let (stream, _) = listener.accept().await?;
+            let requests_tx = requests_tx.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                while let Ok((head, body)) = read_request(&mut stream).await {
+                    let (response, close) = respond(&head);
+                    stream.write_all(response).await?;
+                    requests_tx.send(Received {
+                        connection,
+                        head,
+                        body,
+                    })?;
+                    if close {
+                        break;
+                    }
+                }
+                anyhow::Ok(())
+            });
+        }
+        anyhow::Ok(())
+    });
+
+    Ok((addr, requests_rx))
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header.eq_ignore_ascii_case(name).then_some(value.trim())
+    })
+}
+
+fn http_proxy(addr: SocketAddr) -> Proxy {
+    Proxy {
+        creds: Some(("user".to_string(), "pass".into())),
+        ..proxy(ProxyKind::Http, addr)
+    }
+}
+
+#[tokio::test]
+async fn test_forward_keep_alive() -> anyhow::Result<()> {
+    let (addr, mut requests) =
+        spawn_forward_proxy(|_| (b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", false))
+            .await?;
+
+    let mut proxy = http_proxy(addr);
+    proxy.http.headers = vec![("X-Session".to_string(), "42".to_string())];
+    let forwarder = proxy.forward_http()?;
+
+    let response = forwarder
+        .send(&ForwardRequest::get("http://example.com/index.html#top"))
+        .await?;
+    assert_eq!(response.head.status, 200);
+    assert_eq!(response.body, b"hello");
+
+    let mut origin_form = ForwardRequest::get("/search?q=1");
+    origin_form
+        .headers
+        .push(("Host".to_string(), "example.com:8080".to_string()));
+    forwarder.send(&origin_form).await?;
+
+    let mut post = ForwardRequest::new("POST", "http://EXAMPLE.com?x=y");
+    post.body = b"payload".to_vec();
+    forwarder.send(&post).await?;
+
+    let first = requests.recv().await.unwrap();
+    assert!(first
+        .head
+        .starts_with("GET http://example.com/index.html HTTP/1.1\r\n"));
+    assert_eq!(header(&first.head, "Host"), Some("example.com"));
+    assert_eq!(header(&first.head, "X-Session"), Some("42"));
+    assert_eq!(
+        header(&first.head, "Proxy-Authorization"),
+        Some("Basic dXNlcjpwYXNz")
+    );
+
+    let second = requests.recv().await.unwrap();
+    assert!(second
+        .head
+        .starts_with("GET http://example.com:8080/search?q=1 HTTP/1.1\r\n"));
+    assert_eq!(header(&second.head, "Host"), Some("example.com:8080"));
+
+    let third = requests.recv().await.unwrap();
+    assert!(third
+        .head
+        .starts_with("POST http://EXAMPLE.com/?x=y HTTP/1.1\r\n"));
+    assert_eq!(header(&third.head, "Content-Length"), Some("7"));
+    assert_eq!(third.body, b"payload");
+
+    // every request went through the single kept-alive connection
+    assert_eq!(
+        [first.connection, second.connection, third.connection],
+        [0, 0, 0]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_chunked_and_close() -> anyhow::Result<()> {
+    let (addr, mut requests) = spawn_forward_proxy(|_| {
+        (
+            b"HTTP/1.1 404 Not Found\r\n\
+              Transfer-Encoding: chunked\r\n\
+              Connection: close\r\n\
+              \r\n\
+              4\r\nnot \r\n5\r\nfound\r\n0\r\n\r\n",
+            true,
+        )
+    })
+    .await?;
+
+    let forwarder = http_proxy(addr).forward_http()?;
+    for _ in 0..2 {
+        let response = forwarder
+            .send(&ForwardRequest::get("http://example.com/missing"))
+            .await?;
+        assert_eq!(response.head.status, 404);
+        assert_eq!(response.body, b"not found");
+    }
+
+    assert_eq!(requests.recv().await.unwrap().connection, 0);
+    assert_eq!(requests.recv().await.unwrap().connection, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_stale_connection() -> anyhow::Result<()> {
+    // proxy silently closes connection after every response
+    let (addr, mut requests) =
+        spawn_forward_proxy(|_| (b"HTTP/1.1 204 No Content\r\n\r\n", true)).await?;
+
+    let forwarder = http_proxy(addr).forward_http()?;
+    for _ in 0..2 {
+        let response = forwarder
+            .send(&ForwardRequest::new("DELETE", "http://example.com/item"))
+            .await?;
+        assert_eq!(response.head.status, 204);
+        assert!(response.body.is_empty());
+    }
+
+    assert_eq!(requests.recv().await.unwrap().connection, 0);
+    assert_eq!(requests.recv().await.unwrap().connection, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_post_not_repeated() -> anyhow::Result<()> {
+    // proxy drops connection, once it has received `POST`
+    let (addr, mut requests) = spawn_forward_proxy(|head| match head.starts_with("POST") {
+        true => (b"", true),
+        false => (b"HTTP/1.1 204 No Content\r\n\r\n", false),
+    })
+    .await?;
+
+    let forwarder = http_proxy(addr).forward_http()?;
+    forwarder
+        .send(&ForwardRequest::get("http://example.com/"))
+        .await?;
+
+    let mut post = ForwardRequest::new("POST", "http://example.com/order");
+    post.body = b"buy".to_vec();
+    let error = forwarder.send(&post).await.unwrap_err();
+    assert!(matches!(error, ConnectError::IO { .. }));
+
+    assert_eq!(requests.recv().await.unwrap().connection, 0);
+    let received = requests.recv().await.unwrap();
+    assert_eq!(
+        (received.connection, received.body.as_slice()),
+        (0, &b"buy"[..])
+    );
+    assert!(requests.try_recv().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_post_on_closed_connection() -> anyhow::Result<()> {
+    // proxy closes connection after every response
+    let (addr, mut requests) =
+        spawn_forward_proxy(|_| (b"HTTP/1.1 204 No Content\r\n\r\n", true)).await?;
+
+    let forwarder = http_proxy(addr).forward_http()?;
+    forwarder
+        .send(&ForwardRequest::get("http://example.com/"))
+        .await?;
+    assert_eq!(requests.recv().await.unwrap().connection, 0);
+    // let client notice the close, while connection is idle
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // without body, request is written at once, so writing it doesn't fail
+    let post = ForwardRequest::new("POST", "http://example.com/order");
+    let response = forwarder.send(&post).await?;
+    assert_eq!(response.head.status, 204);
+
+    let received = requests.recv().await.unwrap();
+    assert!(received.head.starts_with("POST"));
+    assert_eq!(received.connection, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_invalid_requests() -> anyhow::Result<()> {
+    let forwarder = http_proxy("127.0.0.1:1".parse()?).forward_http()?;
+
+    for request in [
+        ForwardRequest::get("https://example.com/"),
+        ForwardRequest::get("/no-host"),
+        ForwardRequest::get("http://example.com/ HTTP/1.1\r\nX: y"),
+        ForwardRequest::new("GET /", "http://example.com/"),
+    ] {
+        let error = forwarder.send(&request).await.unwrap_err();
+        assert!(matches!(error, ConnectError::InvalidRequest { .. }));
+        assert_eq!(error.blame(), Blame::Caller);
+    }
+
+    let mut socks = http_proxy("127.0.0.1:1".parse()?);
+    socks.kind = ProxyKind::Socks5;
+    assert!(matches!(
+        socks.forward_http().unwrap_err(),
+        ConnectError::CommandUnsupported
+    ));
+
+    Ok(())
+}
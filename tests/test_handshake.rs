@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use proxied::{ConnectError, ConnectOptions, Proxy, ProxyKind, Socks5Reply, Stage};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+
+use common::{domain_target, proxy};
+
+fn socks5_proxy() -> Proxy {
+    Proxy {
+        creds: Some(("user".to_string(), "pass".into())),
+        ..proxy(ProxyKind::Socks5, "127.0.0.1:1080".parse().unwrap())
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_over_duplex() -> anyhow::Result<()> {
+    let (client, mut server) = tokio::io::duplex(1024);
+
+    let server = tokio::spawn(async move {
+        let mut greeting = [0u8; 4];
+        server.read_exact(&mut greeting).await?;
+        assert_eq!(greeting, [5, 2, 0, 2]);
+        server.write_all(&[5, 2]).await?;
+
+        let mut auth = [0u8; 11];
+        server.read_exact(&mut auth).await?;
+        assert_eq!(&auth, b"\x01\x04user\x04pass");
+        server.write_all(&[1, 0]).await?;
+
+        let mut request = vec![0u8; 5 + "example.com".len() + 2];
+        server.read_exact(&mut request).await?;
+        assert_eq!(&request[..5], &[5, 1, 0, 3, 11]);
+        assert_eq!(&request[5..16], b"example.com");
+        assert_eq!(&request[16..], &443u16.to_be_bytes());
+        server.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+        let mut payload = [0u8; 5];
+        server.read_exact(&mut payload).await?;
+        server.write_all(&payload).await?;
+        anyhow::Ok(())
+    });
+
+    let mut tunnel = socks5_proxy().handshake(client, domain_target()).await?;
+
+    tunnel.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    tunnel.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    server.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_over_duplex_refused() -> anyhow::Result<()> {
+    let (client, mut server) = tokio::io::duplex(1024);
+
+    tokio::spawn(async move {
+        let mut greeting = [0u8; 4];
+        server.read_exact(&mut greeting).await?;
+        server.write_all(&[5, 0]).await?;
+
+        let mut request = vec![0u8; 18];
+        server.read_exact(&mut request).await?;
+        server.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        anyhow::Ok(())
+    });
+
+    let result = socks5_proxy().handshake(client, domain_target()).await;
+    assert!(matches!(
+        result,
+        Err(ConnectError::Socks5 {
+            reply: Socks5Reply::ConnectionRefused
+        })
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_with_timeout() -> anyhow::Result<()> {
+    let (client, mut server) = tokio::io::duplex(1024);
+
+    tokio::spawn(async move {
+        let mut greeting = [0u8; 4];
+        server.read_exact(&mut greeting).await?;
+        std::future::pending::<()>().await;
+        anyhow::Ok(())
+    });
+
+    let options = ConnectOptions {
+        handshake_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let result = socks5_proxy()
+        .handshake_with(client, domain_target(), &options)
+        .await;
+    assert!(matches!(
+        result,
+        Err(ConnectError::Timeout {
+            stage: Stage::Greeting
+        })
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use proxied::{
+    AddrFamily, ConnectError, ConnectOptions, DnsCacheConfig, HappyEyeballs, Proxy, ProxyHost,
+    ProxyKind, Resolver, Stage, StaticResolver,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+mod common;
+
+use common::{proxy_at, target, Socks5Stub};
+
+/// Listener with full accept queue, so connections to it hang like to a broken address
+///
+/// Returned values have to be kept alive for the duration of the test.
+async fn spawn_unresponsive(addr: SocketAddr) -> anyhow::Result<(TcpListener, Vec<TcpStream>)> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(addr)?;
+    let listener = socket.listen(1)?;
+
+    let mut queued = Vec::new();
+    while queued.len() < 64 {
+        match tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await {
+            Ok(stream) => queued.push(stream?),
+            Err(_) => return Ok((listener, queued)),
+        }
+    }
+    anyhow::bail!("accept queue of {} isn't limited", addr)
+}
+
+fn proxy_through(port: u16) -> Proxy {
+    proxy_at(
+        ProxyKind::Socks5,
+        ProxyHost::Domain("proxy.test".to_string()),
+        port,
+    )
+}
+
+fn resolving_to(addrs: &[&str], happy_eyeballs: HappyEyeballs) -> ConnectOptions {
+    let addrs: Vec<IpAddr> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+    let config = DnsCacheConfig {
+        dead_cooldown: Duration::ZERO,
+        ..Default::default()
+    };
+
+    ConnectOptions {
+        resolver: Resolver::with_cache(
+            StaticResolver::new().with_host("proxy.test", addrs),
+            config,
+        ),
+        happy_eyeballs,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_happy_eyeballs_family_order() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    drop(listener);
+
+    let addrs = ["127.0.0.2", "127.0.0.3", "::1"];
+    for (preferred, expected) in [
+        (AddrFamily::Ipv6, ["::1", "127.0.0.2", "127.0.0.3"]),
+        (AddrFamily::Ipv4, ["127.0.0.2", "::1", "127.0.0.3"]),
+    ] {
+        let options = resolving_to(
+            &addrs,
+            HappyEyeballs {
+                preferred,
+                ..Default::default()
+            },
+        );
+        let error = proxy_through(port)
+            .connect_tcp_with(target(), &options)
+            .await
+            .unwrap_err();
+
+        let ConnectError::AllAddrsFailed { attempts } = error else {
+            panic!("unexpected error {:?}", error);
+        };
+        let attempted: Vec<IpAddr> = attempts.iter().map(|(addr, _)| addr.ip()).collect();
+        let expected: Vec<IpAddr> = expected.iter().map(|ip| ip.parse().unwrap()).collect();
+        assert_eq!(attempted, expected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_happy_eyeballs_race() -> anyhow::Result<()> {
+    let port = Socks5Stub::spawn().await?.addr.port();
+    let _unresponsive = spawn_unresponsive(([127, 0, 0, 4], port).into()).await?;
+    let addrs = ["127.0.0.4", "127.0.0.1"];
+
+    // second address joins the race, while the first one hangs
+    let mut options = resolving_to(
+        &addrs,
+        HappyEyeballs {
+            delay: Some(Duration::from_millis(50)),
+            preferred: AddrFamily::Ipv4,
+        },
+    );
+    options.connect_timeout = Some(Duration::from_secs(10));
+
+    let started = Instant::now();
+    let stream = proxy_through(port)
+        .connect_tcp_with(target(), &options)
+        .await?;
+    assert_eq!(
+        stream.info().proxy_addr,
+        Some(([127, 0, 0, 1], port).into())
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // without racing, the first address has to time out
+    let mut options = resolving_to(
+        &addrs,
+        HappyEyeballs {
+            delay: None,
+            preferred: AddrFamily::Ipv4,
+        },
+    );
+    options.connect_timeout = Some(Duration::from_millis(300));
+
+    let started = Instant::now();
+    let stream = proxy_through(port)
+        .connect_tcp_with(target(), &options)
+        .await?;
+    assert_eq!(
+        stream.info().proxy_addr,
+        Some(([127, 0, 0, 1], port).into())
+    );
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_happy_eyeballs_deadline() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    drop(listener);
+
+    // two addresses hang, while the one in between refuses
+    let _first = spawn_unresponsive(([127, 0, 0, 4], port).into()).await?;
+    let _second = spawn_unresponsive(([127, 0, 0, 6], port).into()).await?;
+    let mut options = resolving_to(
+        &["127.0.0.4", "127.0.0.6", "127.0.0.5"],
+        HappyEyeballs {
+            delay: Some(Duration::from_millis(50)),
+            preferred: AddrFamily::Ipv4,
+        },
+    );
+    options.deadline = Some(Duration::from_millis(300));
+
+    let error = proxy_through(port)
+        .connect_tcp_with(target(), &options)
+        .await
+        .unwrap_err();
+
+    let ConnectError::AllAddrsFailed { mut attempts } = error else {
+        panic!("unexpected error {:?}", error);
+    };
+    attempts.sort_by_key(|(addr, _)| *addr);
+    let attempted: Vec<IpAddr> = attempts.iter().map(|(addr, _)| addr.ip()).collect();
+    let expected: Vec<IpAddr> = ["127.0.0.4", "127.0.0.5", "127.0.0.6"]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+    assert_eq!(attempted, expected);
+
+    assert!(matches!(
+        attempts[0].1,
+        ConnectError::Timeout {
+            stage: Stage::ProxyConnect
+        }
+    ));
+    assert!(matches!(
+        attempts[1].1,
+        ConnectError::IO {
+            stage: Stage::ProxyConnect,
+            ..
+        }
+    ));
+    assert!(matches!(
+        attempts[2].1,
+        ConnectError::Timeout {
+            stage: Stage::ProxyConnect
+        }
+    ));
+
+    Ok(())
+}
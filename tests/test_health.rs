@@ -0,0 +1,188 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use proxied::{HealthCheck, PoolStrategy, Proxy, ProxyHealth, ProxyKind, ProxyPool};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+mod common;
+
+use common::{proxy, socks5_greeting, socks5_request, target};
+
+/// Switchable SOCKS5 proxy, which answers `PING` with `PONG` through the tunnel
+struct Switchable {
+    proxy: Proxy,
+    up: Arc<AtomicBool>,
+    accepted: Arc<AtomicUsize>,
+}
+
+impl Switchable {
+    async fn spawn() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let up = Arc::new(AtomicBool::new(true));
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let (state, counter) = (up.clone(), accepted.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                if !state.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                tokio::spawn(async move {
+                    socks5_greeting(&mut stream).await?;
+                    socks5_request(&mut stream, 0).await?;
+
+                    let mut ping = [0u8; 4];
+                    stream.read_exact(&mut ping).await?;
+                    if &ping == b"PING" {
+                        stream.write_all(b"PONG").await?;
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+
+        Ok(Self {
+            proxy: proxy(ProxyKind::Socks5, addr),
+            up,
+            accepted,
+        })
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::SeqCst);
+    }
+
+    fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::SeqCst)
+    }
+}
+
+fn health(failures: u32, ejected: bool) -> ProxyHealth {
+    ProxyHealth { failures, ejected }
+}
+
+#[tokio::test]
+async fn test_health_ejection_backoff() -> anyhow::Result<()> {
+    let flaky = Switchable::spawn().await?;
+    let live = Switchable::spawn().await?;
+    let pool = ProxyPool::new(
+        vec![flaky.proxy.clone(), live.proxy.clone()],
+        PoolStrategy::RoundRobin,
+    )
+    .unwrap();
+    let check = HealthCheck {
+        failure_threshold: 2,
+        base_ejection: Duration::from_millis(300),
+        ..HealthCheck::new(target())
+    };
+
+    flaky.set_up(false);
+    pool.check_health(&check).await;
+    assert_eq!(pool.health(), [health(1, false), health(0, false)]);
+    pool.check_health(&check).await;
+    assert_eq!(pool.health(), [health(2, true), health(0, false)]);
+
+    // ejected proxy is neither picked, nor probed until ejection passes
+    let probed = flaky.accepted();
+    for _ in 0..4 {
+        pool.connect_tcp(target()).await?;
+    }
+    pool.check_health(&check).await;
+    assert_eq!(flaky.accepted(), probed);
+
+    // failed probe after ejection doubles it
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    pool.check_health(&check).await;
+    assert_eq!(flaky.accepted(), probed + 1);
+    assert_eq!(pool.health()[0], health(3, true));
+
+    flaky.set_up(true);
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    pool.check_health(&check).await;
+    assert_eq!(pool.health()[0], health(3, true));
+
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    pool.check_health(&check).await;
+    assert_eq!(pool.health(), [health(0, false), health(0, false)]);
+
+    let accepted = flaky.accepted();
+    pool.connect_tcp(target()).await?;
+    pool.connect_tcp(target()).await?;
+    assert_eq!(flaky.accepted(), accepted + 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_payload() -> anyhow::Result<()> {
+    let proxy = Switchable::spawn().await?;
+    let pool = ProxyPool::new(vec![proxy.proxy.clone()], PoolStrategy::RoundRobin).unwrap();
+
+    let check = HealthCheck {
+        payload: b"PING".to_vec(),
+        expect: b"PONG".to_vec(),
+        failure_threshold: 1,
+        ..HealthCheck::new(target())
+    };
+    pool.check_health(&check).await;
+    assert_eq!(pool.health(), [health(0, false)]);
+
+    let check = HealthCheck {
+        payload: b"PING".to_vec(),
+        expect: b"PANG".to_vec(),
+        ..check
+    };
+    pool.check_health(&check).await;
+    assert_eq!(pool.health(), [health(1, true)]);
+
+    // when every proxy is ejected, they are still tried
+    pool.connect_tcp(target()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_background() -> anyhow::Result<()> {
+    let dead = Switchable::spawn().await?;
+    dead.set_up(false);
+    let live = Switchable::spawn().await?;
+
+    let pool = Arc::new(
+        ProxyPool::new(
+            vec![dead.proxy.clone(), live.proxy.clone()],
+            PoolStrategy::RoundRobin,
+        )
+        .unwrap(),
+    );
+    let check = HealthCheck {
+        interval: Duration::from_millis(20),
+        ..HealthCheck::new(target())
+    };
+
+    let checker = {
+        let pool = pool.clone();
+        tokio::spawn(async move { pool.run_health_checks(&check).await })
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !pool.health()[0].ejected {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    checker.abort();
+
+    assert!(!pool.health()[1].ejected);
+
+    Ok(())
+}
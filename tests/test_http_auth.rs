@@ -0,0 +1,256 @@
+use std::net::SocketAddr;
+
+use md5::{Digest, Md5};
+use proxied::{Blame, ConnectError, HttpAuth, Proxy, ProxyKind};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+mod common;
+
+use common::{domain_target, proxy, read_head};
+
+const REALM: &str = "proxied";
+const NONCE: &str = "dcd98b7102dd2f0e8b11d0f600bfb0c093";
+
+/// HTTP proxy, which answers each request with the reply, produced by `respond`
+///
+/// Request heads are forwarded to the returned channel.
+async fn spawn_http_proxy(
+    respond: fn(&str) -> Vec<u8>,
+) -> anyhow::Result<(SocketAddr, mpsc::UnboundedReceiver<String>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (heads_tx, heads_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = BufReader::new(stream);
+        while let Ok(head) = read_head(&mut stream).await {
+            stream.write_all(&respond(&head)).await?;
+            heads_tx.send(head)?;
+        }
+        anyhow::Ok(())
+    });
+
+    Ok((addr, heads_rx))
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header.eq_ignore_ascii_case(name).then_some(value.trim())
+    })
+}
+
+fn digest_param<'a>(authorization: &'a str, key: &str) -> Option<&'a str> {
+    authorization
+        .strip_prefix("Digest ")?
+        .split(", ")
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            (name == key).then_some(value.trim_matches('"'))
+        })
+}
+
+fn md5_hex(input: String) -> String {
+    Md5::digest(input.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parameters of digest challenge, issued by the proxy
+fn digest_challenge() -> String {
+    format!(
+        "realm=\"{REALM}\", qop=\"auth,auth-int\", nonce=\"{NONCE}\", \
+         opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""
+    )
+}
+
+/// Challenges with `Basic` and `Digest`, each in its own header
+fn digest_responder(head: &str) -> Vec<u8> {
+    let challenges = format!(
+        "Proxy-Authenticate: Basic realm=\"{REALM}\"\r\n\
+         Proxy-Authenticate: Digest {}\r\n",
+        digest_challenge()
+    );
+    respond_digest(head, &challenges)
+}
+
+/// Same as [`digest_responder`], but with all challenges in a single header
+fn combined_digest_responder(head: &str) -> Vec<u8> {
+    let challenges = format!(
+        "Proxy-Authenticate: Basic realm=\"{REALM}\", Digest {}, Negotiate\r\n",
+        digest_challenge()
+    );
+    respond_digest(head, &challenges)
+}
+
+/// Accepts digest response for `user:pass`, answers everything else with `challenges` headers
+fn respond_digest(head: &str, challenges: &str) -> Vec<u8> {
+    let expected = header(head, "Proxy-Authorization").and_then(|authorization| {
+        let cnonce = digest_param(authorization, "cnonce")?;
+        let nc = digest_param(authorization, "nc")?;
+        let uri = digest_param(authorization, "uri")?;
+        let ha1 = md5_hex(format!("user:{}:pass", REALM));
+        let ha2 = md5_hex(format!("CONNECT:{}", uri));
+        let expected = md5_hex(format!("{}:{}:{}:{}:auth:{}", ha1, NONCE, nc, cnonce, ha2));
+
+        let valid = digest_param(authorization, "response")? == expected
+            && digest_param(authorization, "opaque")? == "5ccc069c403ebaf9f0171e9517f40e41"
+            && uri == "example.com:443";
+        Some(valid)
+    });
+
+    match expected {
+        Some(true) => b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec(),
+        _ => format!(
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+             {challenges}\
+             Transfer-Encoding: chunked\r\n\
+             \r\n\
+             5\r\nnope!\r\n0\r\n\r\n"
+        )
+        .into_bytes(),
+    }
+}
+
+fn http_proxy(addr: SocketAddr, creds: Option<(&str, &str)>) -> Proxy {
+    Proxy {
+        creds: creds.map(|(login, password)| (login.to_string(), password.into())),
+        ..proxy(ProxyKind::Http, addr)
+    }
+}
+
+#[tokio::test]
+async fn test_custom_headers_and_bearer() -> anyhow::Result<()> {
+    let (addr, mut heads) =
+        spawn_http_proxy(|_| b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec()).await?;
+
+    let mut proxy = http_proxy(addr, Some(("user", "pass")));
+    proxy.http.auth = HttpAuth::Bearer("token".into());
+    proxy.http.headers = vec![
+        ("X-Session".to_string(), "42".to_string()),
+        ("User-Agent".to_string(), "proxied-test".to_string()),
+    ];
+    proxy.connect_tcp(domain_target()).await?;
+
+    let head = heads.recv().await.unwrap();
+    assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+    assert_eq!(header(&head, "X-Session"), Some("42"));
+    assert_eq!(header(&head, "User-Agent"), Some("proxied-test"));
+    assert_eq!(header(&head, "Proxy-Authorization"), Some("Bearer token"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_auth() -> anyhow::Result<()> {
+    let (addr, mut heads) = spawn_http_proxy(digest_responder).await?;
+
+    let mut proxy = http_proxy(addr, Some(("user", "pass")));
+    proxy.http.auth = HttpAuth::Digest;
+    let stream = proxy.connect_tcp(domain_target()).await?;
+    assert_eq!(stream.info().http_response.as_ref().unwrap().status, 200);
+
+    // credentials are never sent before the challenge
+    let first = heads.recv().await.unwrap();
+    assert_eq!(header(&first, "Proxy-Authorization"), None);
+
+    let second = heads.recv().await.unwrap();
+    let authorization = header(&second, "Proxy-Authorization").unwrap();
+    assert_eq!(digest_param(authorization, "username"), Some("user"));
+    assert!(!second.contains("pass"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_combined_challenges() -> anyhow::Result<()> {
+    let (addr, mut heads) = spawn_http_proxy(combined_digest_responder).await?;
+
+    let mut proxy = http_proxy(addr, Some(("user", "pass")));
+    proxy.http.auth = HttpAuth::Digest;
+    let stream = proxy.connect_tcp(domain_target()).await?;
+    assert_eq!(stream.info().http_response.as_ref().unwrap().status, 200);
+
+    heads.recv().await.unwrap();
+    let second = heads.recv().await.unwrap();
+    let authorization = header(&second, "Proxy-Authorization").unwrap();
+    assert_eq!(digest_param(authorization, "realm"), Some(REALM));
+    assert_eq!(digest_param(authorization, "qop"), Some("auth"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_wrong_password() -> anyhow::Result<()> {
+    let (addr, _heads) = spawn_http_proxy(digest_responder).await?;
+
+    let mut proxy = http_proxy(addr, Some(("user", "wrong")));
+    proxy.http.auth = HttpAuth::Digest;
+    let error = proxy.connect_tcp(domain_target()).await.unwrap_err();
+
+    assert!(matches!(error, ConnectError::AuthFailed { .. }));
+    assert_eq!(error.blame(), Blame::Proxy);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_header_injection_rejected() -> anyhow::Result<()> {
+    let (addr, _heads) =
+        spawn_http_proxy(|_| b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec()).await?;
+
+    let mut proxy = http_proxy(addr, None);
+    proxy.http.headers = vec![(
+        "X-Session".to_string(),
+        "1\r\n\r\nGET / HTTP/1.1".to_string(),
+    )];
+    let error = proxy.connect_tcp(domain_target()).await.unwrap_err();
+
+    assert!(matches!(error, ConnectError::InvalidHeader { .. }));
+    assert_eq!(error.blame(), Blame::Caller);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_authorization_injection_rejected() -> anyhow::Result<()> {
+    let (addr, _heads) =
+        spawn_http_proxy(|_| b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec()).await?;
+
+    let mut proxy = http_proxy(addr, None);
+    proxy.http.auth = HttpAuth::Bearer("token\r\nX-Injected: 1".into());
+    let error = proxy.connect_tcp(domain_target()).await.unwrap_err();
+    assert!(matches!(error, ConnectError::InvalidHeader { .. }));
+
+    // login may carry line breaks, once percent-decoded from the URL
+    let (addr, _heads) = spawn_http_proxy(digest_responder).await?;
+    let mut proxy = http_proxy(addr, Some(("user\r\nX-Injected: 1", "pass")));
+    proxy.http.auth = HttpAuth::Digest;
+    let error = proxy.connect_tcp(domain_target()).await.unwrap_err();
+    assert!(matches!(error, ConnectError::InvalidHeader { .. }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_quoted_strings() -> anyhow::Result<()> {
+    let (addr, mut heads) = spawn_http_proxy(digest_responder).await?;
+
+    let mut proxy = http_proxy(addr, Some(("us\"er\\", "pass")));
+    proxy.http.auth = HttpAuth::Digest;
+    let error = proxy.connect_tcp(domain_target()).await.unwrap_err();
+    assert!(matches!(error, ConnectError::AuthFailed { .. }));
+
+    heads.recv().await.unwrap();
+    let second = heads.recv().await.unwrap();
+    let authorization = header(&second, "Proxy-Authorization").unwrap();
+    assert!(authorization.starts_with(r#"Digest username="us\"er\\", realm="proxied","#));
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use proxied::{
+    Blame, ConnectError, ConnectOptions, Proxy, ProxyHost, ProxyKind, Resolver, Stage,
+    StaticResolver, TlsConfig,
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    RootCertStore, ServerConfig,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+
+mod common;
+
+use common::{domain_target, proxy, proxy_at};
+
+/// Self-signed certificate for `127.0.0.1` and TLS acceptor using it
+fn acceptor() -> (CertificateDer<'static>, TlsAcceptor) {
+    let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert_der = cert.cert.der().clone();
+    let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+
+    (cert_der, TlsAcceptor::from(Arc::new(config)))
+}
+
+/// HTTPS proxy accepting single `CONNECT`, which then echoes tunneled data back
+async fn spawn_https_proxy(
+    acceptor: TlsAcceptor,
+) -> anyhow::Result<(
+    SocketAddr,
+    tokio::task::JoinHandle<anyhow::Result<Vec<String>>>,
+)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = BufReader::new(acceptor.accept(stream).await?);
+
+        let mut request = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+            request.push(line.trim_end().to_string());
+        }
+
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+
+        Ok(request)
+    });
+
+    Ok((addr, handle))
+}
+
+fn https_proxy(addr: SocketAddr) -> Proxy {
+    Proxy {
+        creds: Some(("proxied".to_string(), "secret".into())),
+        ..proxy(ProxyKind::Https, addr)
+    }
+}
+
+#[tokio::test]
+async fn test_https_custom_roots() -> anyhow::Result<()> {
+    let (cert, acceptor) = acceptor();
+    let (addr, server) = spawn_https_proxy(acceptor).await?;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert)?;
+    let options = ConnectOptions {
+        tls: TlsConfig::with_roots(roots),
+        ..Default::default()
+    };
+
+    let mut connection = https_proxy(addr)
+        .connect_tcp_with(domain_target(), &options)
+        .await?;
+    assert!(connection.is_tls());
+
+    connection.write_all(&[1, 2, 3, 4]).await?;
+    let mut buf = [0u8; 4];
+    connection.read_exact(&mut buf).await?;
+    assert_eq!(buf, [1, 2, 3, 4]);
+
+    let request = server.await??;
+    assert!(request[0].starts_with("CONNECT example.com:443"));
+    assert!(request
+        .iter()
+        .any(|line| line.starts_with("Proxy-Authorization: Basic")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_https_insecure() -> anyhow::Result<()> {
+    let (_, acceptor) = acceptor();
+    let (addr, _server) = spawn_https_proxy(acceptor).await?;
+
+    let options = ConnectOptions {
+        tls: TlsConfig::insecure(),
+        ..Default::default()
+    };
+
+    let mut connection = https_proxy(addr)
+        .connect_tcp_with(domain_target(), &options)
+        .await?;
+
+    connection.write_all(&[5, 6, 7, 8]).await?;
+    let mut buf = [0u8; 4];
+    connection.read_exact(&mut buf).await?;
+    assert_eq!(buf, [5, 6, 7, 8]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_https_untrusted_certificate() -> anyhow::Result<()> {
+    let (_, acceptor) = acceptor();
+    let (addr, _server) = spawn_https_proxy(acceptor).await?;
+
+    let result = https_proxy(addr).connect_tcp(domain_target()).await;
+    assert!(matches!(result, Err(ConnectError::Tls(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_https_invalid_server_name() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    // resolves, but isn't valid as SNI
+    let options = ConnectOptions {
+        resolver: Resolver::new(StaticResolver::new().with_host("proxy test", [addr.ip()])),
+        ..Default::default()
+    };
+    let proxy = proxy_at(
+        ProxyKind::Https,
+        ProxyHost::Domain("proxy test".to_string()),
+        addr.port(),
+    );
+
+    let error = proxy
+        .connect_tcp_with(domain_target(), &options)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(error, ConnectError::Tls(_)));
+    assert_eq!(error.stage(), Stage::TlsHandshake);
+    assert_eq!(error.blame(), Blame::Proxy);
+
+    Ok(())
+}
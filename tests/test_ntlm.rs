@@ -0,0 +1,269 @@
+use std::net::SocketAddr;
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use proxied::{Blame, ConnectError, HttpAuth, Proxy, ProxyKind};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+mod common;
+
+use common::{domain_target, proxy, read_head};
+
+const SERVER_CHALLENGE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+const PASSWORD: &str = "SecREt01";
+
+/// Proxy, which requires NTLM handshake on a single kept-alive connection
+///
+/// Decoded `AUTHENTICATE_MESSAGE`s are forwarded to the returned channel.
+async fn spawn_ntlm_proxy() -> anyhow::Result<(SocketAddr, mpsc::UnboundedReceiver<Authenticate>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (messages_tx, messages_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = BufReader::new(stream);
+        while let Ok(head) = read_head(&mut stream).await {
+            let message = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Proxy-Authorization: NTLM "))
+                .and_then(|token| base64::engine::general_purpose::STANDARD.decode(token).ok());
+
+            let reply = match message {
+                Some(message) if message[8] == 1 => format!(
+                    "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                     Proxy-Authenticate: NTLM {}\r\n\
+                     Content-Length: 0\r\n\
+                     \r\n",
+                    base64::engine::general_purpose::STANDARD.encode(challenge_message())
+                ),
+                Some(message) if message[8] == 3 => {
+                    let authenticate = Authenticate::parse(&message);
+                    let valid = authenticate.is_valid(PASSWORD);
+                    messages_tx.send(authenticate)?;
+                    match valid {
+                        true => "HTTP/1.1 200 Connection established\r\n\r\n".to_string(),
+                        false => "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                                  Proxy-Authenticate: NTLM\r\n\
+                                  Content-Length: 0\r\n\
+                                  \r\n"
+                            .to_string(),
+                    }
+                }
+                _ => "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                      Proxy-Authenticate: NTLM\r\n\
+                      Content-Length: 0\r\n\
+                      \r\n"
+                    .to_string(),
+            };
+            stream.write_all(reply.as_bytes()).await?;
+        }
+        anyhow::Ok(())
+    });
+
+    Ok((addr, messages_rx))
+}
+
+fn utf16le(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn from_utf16le(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16(&units).unwrap()
+}
+
+/// `CHALLENGE_MESSAGE` with unicode, target info and server timestamp
+fn challenge_message() -> Vec<u8> {
+    let mut target_info = Vec::new();
+    for (id, value) in [(2u16, utf16le("CORP")), (7, vec![0x11; 8]), (0, vec![])] {
+        target_info.extend_from_slice(&id.to_le_bytes());
+        target_info.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        target_info.extend_from_slice(&value);
+    }
+
+    let mut message = b"NTLMSSP\0".to_vec();
+    message.extend_from_slice(&2u32.to_le_bytes());
+    // empty target name
+    message.extend_from_slice(&[0, 0, 0, 0, 48, 0, 0, 0]);
+    message.extend_from_slice(&0x0088_8205u32.to_le_bytes());
+    message.extend_from_slice(&SERVER_CHALLENGE);
+    message.extend_from_slice(&[0; 8]);
+    let len = (target_info.len() as u16).to_le_bytes();
+    message.extend_from_slice(&[len[0], len[1], len[0], len[1], 48, 0, 0, 0]);
+    message.extend_from_slice(&target_info);
+    message
+}
+
+#[derive(Debug)]
+struct Authenticate {
+    nt_response: Vec<u8>,
+    domain: String,
+    user: String,
+    workstation: String,
+}
+
+impl Authenticate {
+    fn parse(message: &[u8]) -> Self {
+        let field = |offset: usize| {
+            let len = u16::from_le_bytes([message[offset], message[offset + 1]]) as usize;
+            let start =
+                u32::from_le_bytes(message[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            message[start..start + len].to_vec()
+        };
+
+        Self {
+            nt_response: field(20),
+            domain: from_utf16le(&field(28)),
+            user: from_utf16le(&field(36)),
+            workstation: from_utf16le(&field(44)),
+        }
+    }
+
+    /// Checks NTLMv2 proof against `password`, like a domain controller would
+    fn is_valid(&self, password: &str) -> bool {
+        let ntowf = ntowf_v2(password, &self.user, &self.domain);
+        let (proof, blob) = self.nt_response.split_at(16);
+
+        // blob has to carry server timestamp and target info
+        nt_proof(&ntowf, &SERVER_CHALLENGE, blob) == proof && blob[8..16] == [0x11; 8]
+    }
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// `NTOWFv2` of MS-NLMP 3.3.2
+fn ntowf_v2(password: &str, user: &str, domain: &str) -> [u8; 16] {
+    let nt_hash = Md4::digest(utf16le(password));
+    hmac_md5(
+        &nt_hash,
+        &[&utf16le(&user.to_uppercase()), &utf16le(domain)],
+    )
+}
+
+/// `NTProofStr` over the blob, which follows it in `NtChallengeResponse`
+fn nt_proof(ntowf: &[u8; 16], server_challenge: &[u8; 8], blob: &[u8]) -> [u8; 16] {
+    hmac_md5(ntowf, &[server_challenge, blob])
+}
+
+fn hex(value: &str) -> Vec<u8> {
+    (0..value.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&value[index..index + 2], 16).unwrap())
+        .collect()
+}
+
+fn ntlm_proxy(addr: SocketAddr, login: &str, password: &str) -> Proxy {
+    Proxy {
+        creds: Some((login.to_string(), password.into())),
+        ..proxy(ProxyKind::Http, addr)
+    }
+}
+
+#[tokio::test]
+async fn test_ntlm_auth() -> anyhow::Result<()> {
+    let (addr, mut messages) = spawn_ntlm_proxy().await?;
+
+    let mut proxy = ntlm_proxy(addr, "User", PASSWORD);
+    proxy.http.auth = HttpAuth::Ntlm {
+        domain: Some("CORP".to_string()),
+        workstation: Some("BUILD-01".to_string()),
+    };
+    let stream = proxy.connect_tcp(domain_target()).await?;
+    assert_eq!(stream.info().http_response.as_ref().unwrap().status, 200);
+
+    let authenticate = messages.recv().await.unwrap();
+    assert_eq!(authenticate.user, "User");
+    assert_eq!(authenticate.domain, "CORP");
+    assert_eq!(authenticate.workstation, "BUILD-01");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ntlm_domain_in_login() -> anyhow::Result<()> {
+    let (addr, mut messages) = spawn_ntlm_proxy().await?;
+
+    let mut proxy = ntlm_proxy(addr, "CORP\\User", PASSWORD);
+    proxy.http.auth = HttpAuth::Ntlm {
+        domain: None,
+        workstation: None,
+    };
+    proxy.connect_tcp(domain_target()).await?;
+
+    let authenticate = messages.recv().await.unwrap();
+    assert_eq!(authenticate.user, "User");
+    assert_eq!(authenticate.domain, "CORP");
+    assert_eq!(authenticate.workstation, "");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ntlm_wrong_password() -> anyhow::Result<()> {
+    let (addr, _messages) = spawn_ntlm_proxy().await?;
+
+    let mut proxy = ntlm_proxy(addr, "CORP\\User", "wrong");
+    proxy.http.auth = HttpAuth::Ntlm {
+        domain: None,
+        workstation: None,
+    };
+    let error = proxy.connect_tcp(domain_target()).await.unwrap_err();
+
+    assert!(matches!(error, ConnectError::AuthFailed { .. }));
+    assert_eq!(error.blame(), Blame::Proxy);
+
+    Ok(())
+}
+
+/// NTLMv2 authentication example of MS-NLMP 4.2.4, which validates the checks of the proxy above
+#[test]
+fn test_ntlm_known_answers() {
+    let ntowf = ntowf_v2("Password", "User", "Domain");
+    assert_eq!(ntowf.to_vec(), hex("0c868a403bfd7a93a3001ef22ef02e3f"));
+
+    let mut target_info = Vec::new();
+    for (id, value) in [
+        (2u16, utf16le("Domain")),
+        (1, utf16le("Server")),
+        (0, vec![]),
+    ] {
+        target_info.extend_from_slice(&id.to_le_bytes());
+        target_info.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        target_info.extend_from_slice(&value);
+    }
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&[0; 8]);
+    blob.extend_from_slice(&[0xaa; 8]);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let server_challenge = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+    let proof = nt_proof(&ntowf, &server_challenge, &blob);
+    assert_eq!(proof.to_vec(), hex("68cd0ab851e51c96aabc927bebef6a1c"));
+
+    let session_base_key = hmac_md5(&ntowf, &[&proof]);
+    assert_eq!(
+        session_base_key.to_vec(),
+        hex("8de40ccadbc14a82f15cb0ad0de95ca3")
+    );
+
+    let lm_proof = hmac_md5(&ntowf, &[&server_challenge, &[0xaa; 8]]);
+    assert_eq!(lm_proof.to_vec(), hex("86c35097ac9cec102554764a57cccc19"));
+}
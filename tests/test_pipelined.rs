@@ -0,0 +1,118 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use proxied::{NetworkTarget, Proxy, ProxyKind};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+mod common;
+
+const GREETING: &[u8] = b"220 smtp.example.com ESMTP ready\r\n";
+
+/// Proxy, which sends the `CONNECT` response and SMTP greeting in a single write
+async fn spawn_pipelining_proxy() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            if line == "\r\n" {
+                break;
+            }
+        }
+
+        let mut reply = b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec();
+        reply.extend_from_slice(GREETING);
+        stream.write_all(&reply).await?;
+
+        let mut command = [0u8; 6];
+        stream.read_exact(&mut command).await?;
+        stream.write_all(b"250 OK\r\n").await?;
+        anyhow::Ok(())
+    });
+
+    Ok(addr)
+}
+
+fn http_proxy(addr: SocketAddr) -> Proxy {
+    common::proxy(ProxyKind::Http, addr)
+}
+
+fn target() -> NetworkTarget {
+    NetworkTarget::Domain {
+        domain: "smtp.example.com".to_string(),
+        port: 25,
+    }
+}
+
+#[tokio::test]
+async fn test_pipelined_greeting() -> anyhow::Result<()> {
+    let addr = spawn_pipelining_proxy().await?;
+    let mut stream = http_proxy(addr).connect_tcp(target()).await?;
+    assert_eq!(stream.buffered(), GREETING);
+
+    // greeting is read in small pieces, to replay the buffer across several reads
+    let mut greeting = vec![0u8; GREETING.len()];
+    for chunk in greeting.chunks_mut(5) {
+        stream.read_exact(chunk).await?;
+    }
+    assert_eq!(greeting, GREETING);
+    assert!(stream.buffered().is_empty());
+
+    let (mut stream, buffered) = stream.into_inner();
+    assert!(buffered.is_empty());
+    stream.write_all(b"EHLO\r\n").await?;
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(&reply, b"250 OK\r\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipelined_greeting_into_parts() -> anyhow::Result<()> {
+    let addr = spawn_pipelining_proxy().await?;
+    let stream = http_proxy(addr).connect_tcp(target()).await?;
+
+    let (mut stream, info, buffered) = stream.into_parts();
+    assert_eq!(info.http_response.unwrap().status, 200);
+    assert_eq!(buffered, GREETING);
+
+    stream.write_all(b"EHLO\r\n").await?;
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(&reply, b"250 OK\r\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipelined_greeting_handshake() -> anyhow::Result<()> {
+    let (client, mut server) = tokio::io::duplex(1024);
+
+    tokio::spawn(async move {
+        let mut request = vec![0u8; 1024];
+        let len = server.read(&mut request).await?;
+        anyhow::ensure!(request[..len].ends_with(b"\r\n\r\n"), "partial request");
+
+        let mut reply = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        reply.extend_from_slice(GREETING);
+        server.write_all(&reply).await?;
+        anyhow::Ok(())
+    });
+
+    let proxy = http_proxy(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 3128));
+    let mut stream = proxy.handshake(client, target()).await?;
+    assert_eq!(stream.info().http_response.as_ref().unwrap().status, 200);
+
+    let mut greeting = String::new();
+    BufReader::new(&mut stream).read_line(&mut greeting).await?;
+    assert_eq!(greeting.as_bytes(), GREETING);
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use proxied::{Blame, ConnectError, PoolStrategy, Proxy, ProxyKind, ProxyPool, Socks5Reply};
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::{proxy, socks5_greeting, socks5_request, target};
+
+#[derive(Debug, Clone, Copy)]
+enum Behaviour {
+    Accept,
+    /// Never answers the greeting
+    Stall,
+    /// Answers the greeting after delay
+    Slow(Duration),
+    /// Reports target as unreachable
+    HostUnreachable,
+}
+
+/// SOCKS5 proxy, along with count of connections it has accepted
+async fn spawn_proxy(behaviour: Behaviour) -> anyhow::Result<(Proxy, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let accepted = Arc::new(AtomicUsize::new(0));
+
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                match behaviour {
+                    Behaviour::Stall => std::future::pending().await,
+                    Behaviour::Slow(delay) => tokio::time::sleep(delay).await,
+                    _ => {}
+                }
+                socks5_greeting(&mut stream).await?;
+
+                let reply = match behaviour {
+                    Behaviour::HostUnreachable => 4,
+                    _ => 0,
+                };
+                socks5_request(&mut stream, reply).await?;
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    Ok((proxy(ProxyKind::Socks5, addr), accepted))
+}
+
+/// Proxy, where nothing listens
+async fn dead_proxy() -> anyhow::Result<Proxy> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    Ok(proxy(ProxyKind::Socks5, listener.local_addr()?))
+}
+
+fn counts(counters: &[&Arc<AtomicUsize>]) -> Vec<usize> {
+    counters
+        .iter()
+        .map(|counter| counter.load(Ordering::SeqCst))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_pool_round_robin() -> anyhow::Result<()> {
+    let (first, first_count) = spawn_proxy(Behaviour::Accept).await?;
+    let (second, second_count) = spawn_proxy(Behaviour::Accept).await?;
+    let (third, third_count) = spawn_proxy(Behaviour::Accept).await?;
+
+    let pool = ProxyPool::new(vec![first, second, third], PoolStrategy::RoundRobin).unwrap();
+    for _ in 0..2 {
+        for proxy in pool.proxies() {
+            let stream = pool.connect_tcp(target()).await?;
+            assert_eq!(stream.info().proxy_addr.unwrap().port(), proxy.port);
+        }
+    }
+    assert_eq!(
+        counts(&[&first_count, &second_count, &third_count]),
+        [2, 2, 2]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_retries_proxy_failures() -> anyhow::Result<()> {
+    let (live, live_count) = spawn_proxy(Behaviour::Accept).await?;
+    let dead = dead_proxy().await?;
+
+    let pool = ProxyPool::new(vec![dead.clone(), live], PoolStrategy::RoundRobin).unwrap();
+    pool.connect_tcp(target()).await?;
+    assert_eq!(live_count.load(Ordering::SeqCst), 1);
+
+    let single = ProxyPool::new(vec![dead.clone(), dead], PoolStrategy::RoundRobin)
+        .unwrap()
+        .with_max_attempts(1);
+    let error = single.connect_tcp(target()).await.unwrap_err();
+    assert_eq!(error.blame(), Blame::Proxy);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_keeps_target_failures() -> anyhow::Result<()> {
+    let (unreachable, unreachable_count) = spawn_proxy(Behaviour::HostUnreachable).await?;
+    let (live, live_count) = spawn_proxy(Behaviour::Accept).await?;
+
+    let pool = ProxyPool::new(vec![unreachable, live], PoolStrategy::RoundRobin).unwrap();
+    let error = pool.connect_tcp(target()).await.unwrap_err();
+
+    assert!(matches!(
+        error,
+        ConnectError::Socks5 {
+            reply: Socks5Reply::HostUnreachable
+        }
+    ));
+    assert_eq!(counts(&[&unreachable_count, &live_count]), [1, 0]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_weighted() -> anyhow::Result<()> {
+    let (light, light_count) = spawn_proxy(Behaviour::Accept).await?;
+    let (heavy, heavy_count) = spawn_proxy(Behaviour::Accept).await?;
+
+    let pool =
+        ProxyPool::weighted(vec![(light.clone(), 0), (heavy, 1)], PoolStrategy::Weighted).unwrap();
+    for _ in 0..5 {
+        pool.connect_tcp(target()).await?;
+    }
+    assert_eq!(counts(&[&light_count, &heavy_count]), [0, 5]);
+
+    assert!(ProxyPool::weighted(vec![(light, 0)], PoolStrategy::Weighted).is_none());
+    assert!(ProxyPool::new(Vec::new(), PoolStrategy::RoundRobin).is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_random() -> anyhow::Result<()> {
+    let (first, first_count) = spawn_proxy(Behaviour::Accept).await?;
+    let (second, second_count) = spawn_proxy(Behaviour::Accept).await?;
+
+    let pool = ProxyPool::new(vec![first, second], PoolStrategy::Random).unwrap();
+    for _ in 0..32 {
+        pool.connect_tcp(target()).await?;
+    }
+
+    let counts = counts(&[&first_count, &second_count]);
+    assert_eq!(counts.iter().sum::<usize>(), 32);
+    assert!(counts.iter().all(|count| *count > 0));
+
+    Ok(())
+}
+
+/// Scanning and sampling strategies avoid proxy with pending connection
+#[tokio::test]
+async fn test_pool_least_loaded() -> anyhow::Result<()> {
+    for strategy in [PoolStrategy::LeastInFlight, PoolStrategy::PowerOfTwoChoices] {
+        let (stalled, stalled_count) = spawn_proxy(Behaviour::Stall).await?;
+        let (live, live_count) = spawn_proxy(Behaviour::Accept).await?;
+        let stalled_port = stalled.port;
+
+        let pool = Arc::new(ProxyPool::new(vec![stalled, live], strategy).unwrap());
+
+        // occupy the stalled proxy, sampling may pick the live one a few times first
+        let mut pending = Vec::new();
+        while stalled_count.load(Ordering::SeqCst) == 0 {
+            let pool = pool.clone();
+            pending.push(tokio::spawn(
+                async move { pool.connect_tcp(target()).await },
+            ));
+            while stalled_count.load(Ordering::SeqCst) == 0
+                && !pending.last().unwrap().is_finished()
+            {
+                tokio::task::yield_now().await;
+            }
+        }
+        // streams, which went through the live proxy, are dropped along with their tasks
+        pending.retain(|task| !task.is_finished());
+        let occupied = live_count.load(Ordering::SeqCst);
+
+        for _ in 0..5 {
+            let stream = pool.connect_tcp(target()).await?;
+            assert_ne!(stream.info().proxy_addr.unwrap().port(), stalled_port);
+        }
+        assert_eq!(live_count.load(Ordering::SeqCst), occupied + 5);
+        pending.iter().for_each(|task| task.abort());
+    }
+
+    Ok(())
+}
+
+/// Established connections load the proxy, until they are dropped
+#[tokio::test]
+async fn test_pool_least_in_flight_streams() -> anyhow::Result<()> {
+    let (first, first_count) = spawn_proxy(Behaviour::Accept).await?;
+    let (second, second_count) = spawn_proxy(Behaviour::Accept).await?;
+
+    let pool = ProxyPool::new(vec![first, second], PoolStrategy::LeastInFlight).unwrap();
+    let held = pool.connect_tcp(target()).await?;
+    let other = pool.connect_tcp(target()).await?;
+    assert_ne!(held.info().proxy_addr, other.info().proxy_addr);
+    assert_eq!(counts(&[&first_count, &second_count]), [1, 1]);
+
+    drop(other);
+    for _ in 0..3 {
+        let stream = pool.connect_tcp(target()).await?;
+        assert_ne!(stream.info().proxy_addr, held.info().proxy_addr);
+    }
+
+    drop(held);
+    let held = pool.connect_tcp(target()).await?;
+    let other = pool.connect_tcp(target()).await?;
+    assert_ne!(held.info().proxy_addr, other.info().proxy_addr);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_ewma_latency() -> anyhow::Result<()> {
+    let (slow, slow_count) = spawn_proxy(Behaviour::Slow(Duration::from_millis(200))).await?;
+    let (fast, fast_count) = spawn_proxy(Behaviour::Accept).await?;
+
+    let pool = ProxyPool::new(vec![slow, fast], PoolStrategy::EwmaLatency).unwrap();
+
+    // unmeasured proxies go first
+    pool.connect_tcp(target()).await?;
+    pool.connect_tcp(target()).await?;
+    assert_eq!(counts(&[&slow_count, &fast_count]), [1, 1]);
+
+    for _ in 0..5 {
+        pool.connect_tcp(target()).await?;
+    }
+    assert_eq!(counts(&[&slow_count, &fast_count]), [1, 6]);
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use proxied::{
+    ConnectOptions, DnsMode, NetworkTarget, ProxyHost, ProxyKind, Resolve, Resolver, Resolving,
+    Stage, StaticResolver,
+};
+
+mod common;
+
+use common::{proxy_at, Socks5Stub};
+
+/// Resolver stub, which counts lookups
+#[derive(Default)]
+struct Counting {
+    lookups: Arc<AtomicUsize>,
+}
+
+impl Resolve for Counting {
+    fn resolve<'a>(&'a self, domain: &'a str) -> Resolving<'a> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            match domain {
+                "target.test" => {
+                    Ok(vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])].into())
+                }
+                _ => Ok(Vec::new().into()),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_static_resolver() -> anyhow::Result<()> {
+    let Socks5Stub {
+        addr, mut targets, ..
+    } = Socks5Stub::spawn().await?;
+
+    let hosts = StaticResolver::new()
+        .with_host("proxy.test", [addr.ip()])
+        .with_host("Target.Test", ["192.0.2.7".parse()?]);
+    let options = ConnectOptions {
+        dns: DnsMode::Local,
+        resolver: Resolver::new(hosts),
+        ..Default::default()
+    };
+
+    let proxy = proxy_at(
+        ProxyKind::Socks5,
+        ProxyHost::Domain("proxy.test".to_string()),
+        addr.port(),
+    );
+    let target = NetworkTarget::Domain {
+        domain: "target.test".to_string(),
+        port: 443,
+    };
+    let stream = proxy.connect_tcp_with(target, &options).await?;
+
+    assert_eq!(stream.info().proxy_addr, Some(addr));
+    assert_eq!(targets.recv().await.unwrap().to_string(), "192.0.2.7:443");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolver_cache_round_robin() -> anyhow::Result<()> {
+    let Socks5Stub {
+        addr, mut targets, ..
+    } = Socks5Stub::spawn().await?;
+
+    let resolve = Counting::default();
+    let lookups = resolve.lookups.clone();
+    let options = ConnectOptions {
+        dns: DnsMode::Local,
+        resolver: Resolver::new(resolve),
+        ..Default::default()
+    };
+
+    let proxy = proxy_at(ProxyKind::Socks5, addr.ip().into(), addr.port());
+    for port in [80, 81, 82] {
+        let target = NetworkTarget::Domain {
+            domain: "target.test".to_string(),
+            port,
+        };
+        proxy.connect_tcp_with(target, &options).await?;
+    }
+
+    assert_eq!(targets.recv().await.unwrap().to_string(), "10.0.0.1:80");
+    assert_eq!(targets.recv().await.unwrap().to_string(), "10.0.0.2:81");
+    assert_eq!(targets.recv().await.unwrap().to_string(), "10.0.0.1:82");
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolver_errors() -> anyhow::Result<()> {
+    let options = ConnectOptions {
+        resolver: Resolver::new(Counting::default()),
+        ..Default::default()
+    };
+    let target = NetworkTarget::Domain {
+        domain: "target.test".to_string(),
+        port: 443,
+    };
+
+    // no records
+    let proxy = proxy_at(
+        ProxyKind::Socks5,
+        ProxyHost::Domain("empty.test".to_string()),
+        1080,
+    );
+    let error = proxy
+        .connect_tcp_with(target.clone(), &options)
+        .await
+        .unwrap_err();
+    assert_eq!(error.stage(), Stage::ProxyDns);
+
+    // unknown host of static resolver
+    let options = ConnectOptions {
+        resolver: Resolver::new(StaticResolver::new()),
+        ..Default::default()
+    };
+    let proxy = proxy_at(
+        ProxyKind::Socks5,
+        ProxyHost::Domain("proxy.test".to_string()),
+        1080,
+    );
+    let error = proxy.connect_tcp_with(target, &options).await.unwrap_err();
+    assert_eq!(error.stage(), Stage::ProxyDns);
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use proxied::{ConnectError, NetworkTarget, Proxy, ProxyKind, Secret};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+mod common;
+
+use common::proxy;
+
+async fn read_cstr(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0 {
+            return Ok(out);
+        }
+        out.push(byte);
+    }
+}
+
+/// Reads SOCKS4(a) request and returns (port, ip, userid, domain)
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<(u16, [u8; 4], Vec<u8>, Vec<u8>)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    anyhow::ensure!(header[0] == 4 && header[1] == 1, "not a SOCKS4 CONNECT");
+
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let ip = [header[4], header[5], header[6], header[7]];
+
+    let userid = read_cstr(stream).await?;
+    let domain = match ip {
+        [0, 0, 0, x] if x != 0 => read_cstr(stream).await?,
+        _ => Vec::new(),
+    };
+
+    Ok((port, ip, userid, domain))
+}
+
+/// Single-connection SOCKS4 server, which answers with `reply_code` and echoes data back
+async fn spawn_socks4_server(
+    reply_code: u8,
+) -> anyhow::Result<(
+    SocketAddr,
+    tokio::task::JoinHandle<anyhow::Result<(u16, [u8; 4], Vec<u8>, Vec<u8>)>>,
+)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let request = read_request(&mut stream).await?;
+
+        stream.write_all(&[0, reply_code, 0, 0, 0, 0, 0, 0]).await?;
+
+        let mut buf = [0u8; 4];
+        if reply_code == 0x5A {
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+        }
+
+        Ok(request)
+    });
+
+    Ok((addr, handle))
+}
+
+fn proxy_for(kind: ProxyKind, addr: SocketAddr) -> Proxy {
+    Proxy {
+        creds: Some(("proxied".to_string(), Secret::default())),
+        ..proxy(kind, addr)
+    }
+}
+
+#[tokio::test]
+async fn test_socks4_ip_target() -> anyhow::Result<()> {
+    let (addr, server) = spawn_socks4_server(0x5A).await?;
+    let proxy = proxy_for(ProxyKind::Socks4, addr);
+
+    let mut connection = proxy
+        .connect_tcp(NetworkTarget::IPAddr {
+            socket: "10.1.2.3:4242".parse()?,
+        })
+        .await?;
+
+    connection.write_all(&[1, 2, 3, 4]).await?;
+    let mut buf = [0u8; 4];
+    connection.read_exact(&mut buf).await?;
+    assert_eq!(buf, [1, 2, 3, 4]);
+
+    let (port, ip, userid, domain) = server.await??;
+    assert_eq!(port, 4242);
+    assert_eq!(ip, [10, 1, 2, 3]);
+    assert_eq!(userid, b"proxied");
+    assert!(domain.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks4a_domain_target() -> anyhow::Result<()> {
+    let (addr, server) = spawn_socks4_server(0x5A).await?;
+    let proxy = Proxy::from_str(&format!("socks4a://{}:{}", addr.ip(), addr.port()))?;
+    assert_eq!(proxy.kind, ProxyKind::Socks4a);
+
+    let mut connection = proxy
+        .connect_tcp(NetworkTarget::Domain {
+            domain: "example.invalid".to_string(),
+            port: 80,
+        })
+        .await?;
+
+    connection.write_all(&[5, 6, 7, 8]).await?;
+    let mut buf = [0u8; 4];
+    connection.read_exact(&mut buf).await?;
+
+    let (port, ip, _, domain) = server.await??;
+    assert_eq!(port, 80);
+    assert_eq!(ip, [0, 0, 0, 1]);
+    assert_eq!(domain, b"example.invalid");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks4_rejected() -> anyhow::Result<()> {
+    let (addr, _server) = spawn_socks4_server(0x5B).await?;
+    let proxy = proxy_for(ProxyKind::Socks4, addr);
+
+    let result = proxy
+        .connect_tcp(NetworkTarget::IPAddr {
+            socket: "10.1.2.3:4242".parse()?,
+        })
+        .await;
+
+    assert!(matches!(result, Err(ConnectError::Socks4Rejected)));
+    Ok(())
+}
@@ -5,11 +5,8 @@ use fast_socks5::{
 };
 use futures::{Future, StreamExt};
 use proxied::Proxy;
-use std::{convert::Infallible, ops::Deref, sync::LazyLock};
-use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    task::JoinSet,
-};
+use std::{convert::Infallible, sync::LazyLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const SOCKS_SERVER_LISTENER_PORT: u16 = 1034;
 #[derive(Debug)]
@@ -30,7 +27,6 @@ struct Opt {
 /// Choose the authentication type
 #[derive(Debug)]
 enum AuthMode {
-    NoAuth,
     Password { username: String, password: String },
 }
 
@@ -60,15 +56,11 @@ where
     })
 }
 async fn run_socks5_server(opt: Opt) -> anyhow::Result<Infallible> {
-    let mut config = Config::default();
+    let mut config = <Config>::default();
     config.set_request_timeout(opt.request_timeout);
     config.set_skip_auth(opt.skip_auth);
 
     let config = match opt.auth {
-        AuthMode::NoAuth => {
-            tracing::warn!("No authentication has been set!");
-            config
-        }
         AuthMode::Password { username, password } => {
             if opt.skip_auth {
                 return Err(SocksError::ArgumentInputError(
@@ -123,9 +115,13 @@ async fn test_socks5_password() -> anyhow::Result<()> {
 
     let proxy = Proxy {
         kind: proxied::ProxyKind::Socks5,
-        addr: "127.0.0.1".to_string(),
+        addr: "127.0.0.1".parse().unwrap(),
         port: SOCKS_SERVER_LISTENER_PORT,
-        creds: Some((PROXY_USER.username.clone(), PROXY_USER.password.clone())),
+        creds: Some((
+            PROXY_USER.username.clone(),
+            PROXY_USER.password.clone().into(),
+        )),
+        http: Default::default(),
         refresh_url: None,
     };
 
@@ -140,18 +136,18 @@ async fn test_socks5_password() -> anyhow::Result<()> {
 
     // send one byte to echo_bin
 
-    let VERIFICATION_SLICE: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+    let verification_slice: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
 
-    if connection.write(VERIFICATION_SLICE).await? == 0 {
+    if connection.write(verification_slice).await? == 0 {
         tracing::error!(event = "client.unexpected_close");
         bail!("connection closed");
     }
 
-    let mut recv_buffer: Vec<u8> = vec![0; VERIFICATION_SLICE.len()];
+    let mut recv_buffer: Vec<u8> = vec![0; verification_slice.len()];
 
-    if connection.read(recv_buffer.as_mut_slice()).await? == 0 {}
+    connection.read_exact(recv_buffer.as_mut_slice()).await?;
 
-    if recv_buffer.as_slice() == VERIFICATION_SLICE {
+    if recv_buffer.as_slice() == verification_slice {
         tracing::info!(event = "client.slice.ok");
     } else {
         tracing::error!(event = "client.unexpected_close", action = "read");
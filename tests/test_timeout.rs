@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use proxied::{Blame, ConnectError, ConnectOptions, Proxy, ProxyKind, Stage};
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::{proxy, target};
+
+/// Proxy, which accepts connections, but never answers
+async fn silent_proxy() -> anyhow::Result<(Proxy, tokio::task::JoinHandle<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.push(stream);
+        }
+    });
+
+    Ok((proxy(ProxyKind::Socks5, addr), handle))
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() -> anyhow::Result<()> {
+    let (proxy, _server) = silent_proxy().await?;
+
+    let options = ConnectOptions {
+        handshake_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let result = proxy.connect_tcp_with(target(), &options).await;
+    assert!(matches!(
+        result,
+        Err(ConnectError::Timeout {
+            stage: Stage::Greeting
+        })
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_overall_deadline() -> anyhow::Result<()> {
+    let (proxy, _server) = silent_proxy().await?;
+
+    let options = ConnectOptions {
+        handshake_timeout: Some(Duration::from_secs(30)),
+        deadline: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let started = std::time::Instant::now();
+    let result = proxy.connect_tcp_with(target(), &options).await;
+
+    assert!(matches!(
+        result,
+        Err(ConnectError::Timeout {
+            stage: Stage::Greeting
+        })
+    ));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    Ok(())
+}
+
+/// Protocols without greeting blame the proxy, which hasn't replied to the request at all
+#[tokio::test]
+async fn test_reply_timeout() -> anyhow::Result<()> {
+    for kind in [ProxyKind::Http, ProxyKind::Socks4] {
+        let (mut proxy, _server) = silent_proxy().await?;
+        proxy.kind = kind;
+
+        let options = ConnectOptions {
+            handshake_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+
+        let error = proxy
+            .connect_tcp_with(target(), &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ConnectError::Timeout {
+                stage: Stage::Reply
+            }
+        ));
+        assert_eq!(error.blame(), Blame::Proxy);
+    }
+
+    Ok(())
+}
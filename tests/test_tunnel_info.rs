@@ -0,0 +1,91 @@
+use proxied::{NetworkTarget, ProxyKind};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+mod common;
+
+use common::{domain_target, proxy, socks5_greeting};
+
+#[tokio::test]
+async fn test_socks5_bound_addr() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        socks5_greeting(&mut stream).await?;
+
+        // domain request: header, length, 11 byte domain, port
+        let mut request = [0u8; 4 + 1 + 11 + 2];
+        stream.read_exact(&mut request).await?;
+        stream
+            .write_all(&[5, 0, 0, 1, 203, 0, 113, 7, 0x30, 0x39])
+            .await?;
+
+        let _ = stream.read(&mut [0u8; 1]).await;
+        anyhow::Ok(())
+    });
+
+    let proxy = proxy(ProxyKind::Socks5, addr);
+    let stream = proxy.connect_tcp(domain_target()).await?;
+    let info = stream.info();
+
+    assert_eq!(info.proxy, proxy);
+    assert_eq!(info.proxy_addr, Some(addr));
+    assert_eq!(
+        info.bound_addr,
+        Some(NetworkTarget::IPAddr {
+            socket: "203.0.113.7:12345".parse()?
+        })
+    );
+    assert!(info.http_response.is_none());
+    assert!(!stream.is_tls());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_response_headers() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            if line == "\r\n" {
+                break;
+            }
+        }
+
+        stream
+            .write_all(
+                b"HTTP/1.1 200 Connection established\r\n\
+                  X-Session-Id: abc123\r\n\
+                  X-Exit-IP:  198.51.100.4 \r\n\
+                  \r\n",
+            )
+            .await?;
+
+        let _ = stream.read(&mut [0u8; 1]).await;
+        anyhow::Ok(())
+    });
+
+    let stream = proxy(ProxyKind::Http, addr)
+        .connect_tcp(domain_target())
+        .await?;
+    let response = stream.info().http_response.as_ref().unwrap();
+
+    assert_eq!(response.status, 200);
+    assert_eq!(response.reason, "Connection established");
+    assert_eq!(response.header("x-session-id"), Some("abc123"));
+    assert_eq!(response.header("X-Exit-Ip"), Some("198.51.100.4"));
+    assert_eq!(response.headers.len(), 2);
+    assert!(stream.info().bound_addr.is_none());
+
+    Ok(())
+}
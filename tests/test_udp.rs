@@ -0,0 +1,124 @@
+use std::{io::ErrorKind, net::SocketAddr, time::Duration};
+
+use proxied::{Blame, ConnectError, NetworkTarget, ProxyKind};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::oneshot,
+};
+
+mod common;
+
+use common::{proxy, socks5_greeting};
+
+/// SOCKS5 proxy, which echoes every datagram back to the client with the same header
+///
+/// Relay address is reported as `0.0.0.0`, so client has to substitute proxy address.
+/// Returned receiver completes when the control connection is closed by the client.
+async fn spawn_udp_proxy() -> anyhow::Result<(SocketAddr, oneshot::Receiver<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_port = relay.local_addr()?.port();
+    let (closed_tx, closed_rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let (mut control, _) = listener.accept().await?;
+        socks5_greeting(&mut control).await?;
+
+        let mut request = [0u8; 10];
+        control.read_exact(&mut request).await?;
+        anyhow::ensure!(request[1] == 3, "expected UDP ASSOCIATE");
+
+        let mut reply = vec![5, 0, 0, 1, 0, 0, 0, 0];
+        reply.extend_from_slice(&relay_port.to_be_bytes());
+        control.write_all(&reply).await?;
+
+        tokio::spawn(async move {
+            let mut packet = [0u8; 1024];
+            while let Ok((len, from)) = relay.recv_from(&mut packet).await {
+                relay.send_to(&packet[..len], from).await?;
+            }
+            anyhow::Ok(())
+        });
+
+        let _ = control.read(&mut [0u8; 1]).await;
+        let _ = closed_tx.send(());
+        anyhow::Ok(())
+    });
+
+    Ok((addr, closed_rx))
+}
+
+#[tokio::test]
+async fn test_udp_associate() -> anyhow::Result<()> {
+    let (addr, closed) = spawn_udp_proxy().await?;
+    let socket = proxy(ProxyKind::Socks5, addr).associate_udp().await?;
+    assert_eq!(socket.relay_addr().ip(), addr.ip());
+
+    for target in [
+        NetworkTarget::Domain {
+            domain: "dns.example".to_string(),
+            port: 53,
+        },
+        NetworkTarget::IPAddr {
+            socket: "[2001:db8::1]:443".parse()?,
+        },
+    ] {
+        let sent = socket.send_to(b"datagram", &target).await?;
+        assert_eq!(sent, 8);
+
+        let mut buf = [0u8; 64];
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], b"datagram");
+        assert_eq!(from, target);
+    }
+
+    drop(socket);
+    closed.await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_association_closed() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_port = relay.local_addr()?.port();
+
+    // proxy ends association right after establishing it, while relay stays silent
+    tokio::spawn(async move {
+        let (mut control, _) = listener.accept().await?;
+        socks5_greeting(&mut control).await?;
+
+        let mut request = [0u8; 10];
+        control.read_exact(&mut request).await?;
+        let mut reply = vec![5, 0, 0, 1, 127, 0, 0, 1];
+        reply.extend_from_slice(&relay_port.to_be_bytes());
+        control.write_all(&reply).await?;
+        anyhow::Ok(())
+    });
+
+    let socket = proxy(ProxyKind::Socks5, addr).associate_udp().await?;
+    let mut buf = [0u8; 64];
+    let error = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await?
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+    drop(relay);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_unsupported_kind() -> anyhow::Result<()> {
+    let mut proxy = proxy(ProxyKind::Socks5, "127.0.0.1:1".parse()?);
+    proxy.kind = ProxyKind::Http;
+
+    let error = proxy.associate_udp().await.unwrap_err();
+    assert!(matches!(error, ConnectError::CommandUnsupported));
+    assert_eq!(error.blame(), Blame::Caller);
+
+    Ok(())
+}